@@ -0,0 +1,387 @@
+use tokio::io;
+use log::info;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use pnet::ipnetwork::Ipv4Network;
+
+use simp_drop::{Accepter, ClientConnector, Discovery};
+use simp_drop::config::Config;
+use simp_drop::controller::discoverer::CAP_ENCRYPTION;
+use simp_drop::controller::receiver::Receiver;
+use simp_drop::controller::signing::{Announcer, Verifier};
+use simp_drop::controller::subscriber::Subscribers;
+use simp_drop::controller::transfers::Transfers;
+use simp_drop::device::{self, Device, DeviceInfo, RemoteTcpDevice};
+use simp_drop::identity::{self, Identity};
+use simp_drop::interfaces;
+use simp_drop::known_peers::KnownPeers;
+use simp_drop::settings::Settings;
+
+const USAGE: &str = "usage:
+  rsdrop list [--timeout <secs>] [--json]
+  rsdrop send [--timeout <secs>] <device id | name | ip[:port]> <files...>
+  rsdrop receive [dir]
+every command takes --config <file> instead of the default config file";
+
+/// seconds to wait for devices to answer
+const DEFAULT_TIMEOUT: u64 = 3;
+/// characters of the progress bar
+const BAR_WIDTH: usize = 30;
+
+#[tokio::main]
+async fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("list") => list(&args[1..]).await,
+        Some("send") => send(&args[1..]).await,
+        Some("receive") => receive(&args[1..]).await,
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, USAGE)),
+    };
+    if let Err(e) = result {
+        eprintln!("rsdrop: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// the options of a command and its other arguments
+struct Options {
+    timeout: Duration,
+    json: bool,
+    config: Option<PathBuf>,
+    args: Vec<String>,
+}
+
+fn parse_options(args: &[String]) -> io::Result<Options> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, format!("{}\n{}", msg, USAGE));
+    let mut options = Options {
+        timeout: Duration::from_secs(DEFAULT_TIMEOUT),
+        json: false,
+        config: None,
+        args: Vec::new(),
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--timeout" => {
+                let secs = args.next().ok_or_else(|| invalid("--timeout needs a value".to_string()))?;
+                let secs: u64 = secs.parse().map_err(|_| invalid(format!("invalid timeout {}", secs)))?;
+                options.timeout = Duration::from_secs(secs);
+            },
+            "--json" => options.json = true,
+            "--config" => {
+                let path = args.next().ok_or_else(|| invalid("--config needs a file".to_string()))?;
+                options.config = Some(path.into());
+            },
+            other if other.starts_with("--") => return Err(invalid(format!("unknown option {}", other))),
+            other => options.args.push(other.to_string()),
+        }
+    }
+    Ok(options)
+}
+
+/// config, identity and pinned keys, as the app uses them
+struct Context {
+    config: Config,
+    identity: Identity,
+    pins: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl Context {
+    fn load(options: &Options) -> io::Result<Self> {
+        let config = Config::load_at(options.config.as_deref())?;
+        let mut identity = Identity::load_or_create()?;
+        identity.device.name = config.device_name();
+        Ok(Self {
+            config,
+            identity,
+            pins: Arc::new(Mutex::new(KnownPeers::load().pins())),
+        })
+    }
+
+    /// start discovery on every selected interface, found devices go to `tx` and
+    /// the `probe` addresses get a unicast request. it announces the accepter port in
+    /// `ports`, or the configured one. stops when the result is dropped.
+    async fn start_discovery(&self, tx: tokio::sync::mpsc::Sender<RemoteTcpDevice>, probe: &[IpAddr], ports: &HashMap<Ipv4Network, u16>) -> io::Result<Vec<Discovery>> {
+        let settings = Settings::load();
+        let announcer = Announcer::new(self.identity.device.clone(), &self.identity.private_key, settings.visibility, settings.rooms);
+        let verifier = Arc::new(Verifier::new(self.pins.clone()));
+        let mut services = Vec::new();
+        let mut failed = None;
+        for (name, network) in interfaces::selected_networks(&self.config.interfaces) {
+            let port = ports.get(&network).copied().unwrap_or(self.config.receive.port);
+            let mut discovery = match Discovery::new(&network, &self.config.discovery, port).await {
+                Ok(discovery) => discovery,
+                Err(e) => {
+                    info!("can't discover on {} {}: {}", name, network, e);
+                    failed = Some(e);
+                    continue;
+                },
+            };
+            let probe_tx = discovery.start(&announcer, verifier.clone(), tx.clone()).await?;
+            for ip in probe {
+                let _ = probe_tx.send(ip.to_string()).await;
+            }
+            services.push(discovery);
+        }
+        if services.is_empty() {
+            return Err(match failed {
+                Some(e) => e.into(),
+                None => io::Error::new(io::ErrorKind::AddrNotAvailable, "no usable network interface, see simp_drop --list-interfaces"),
+            });
+        }
+        Ok(services)
+    }
+
+    /// collect the devices answering within `wait`, one entry per device
+    async fn discover(&self, wait: Duration, probe: &[IpAddr]) -> io::Result<Vec<RemoteTcpDevice>> {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(32);
+        let _services = self.start_discovery(tx, probe, &HashMap::new()).await?;
+        let mut devices: Vec<RemoteTcpDevice> = Vec::new();
+        let deadline = tokio::time::sleep(wait);
+        tokio::pin!(deadline);
+        loop {
+            tokio::select! {
+                _ = &mut deadline => break,
+                device = rx.recv() => match device {
+                    // a device going offline, signed with the key it announced
+                    Some(d) if d.left => devices.retain(|old| old.device.id != d.device.id || old.fingerprint != d.fingerprint),
+                    Some(d) => match devices.iter_mut().find(|old| old.device.id == d.device.id) {
+                        Some(old) => old.merge(d),
+                        None => devices.push(d),
+                    },
+                    None => break,
+                },
+            }
+        }
+        Ok(devices)
+    }
+
+    /// whether `addr` is in the network of one of our interfaces
+    fn is_local(&self, addr: &SocketAddr) -> bool {
+        match addr.ip() {
+            IpAddr::V4(ip) => interfaces::selected_networks(&self.config.interfaces).iter().any(|(_, n)| n.contains(ip)),
+            _ => false,
+        }
+    }
+}
+
+/// discover for a while and print the devices
+async fn list(args: &[String]) -> io::Result<()> {
+    let options = parse_options(args)?;
+    let ctx = Context::load(&options)?;
+    let devices = ctx.discover(options.timeout, &[]).await?;
+
+    if options.json {
+        let list: Vec<DeviceInfo> = devices.iter().map(DeviceInfo::from).collect();
+        println!("{}", serde_json::to_string_pretty(&list)?);
+        return Ok(());
+    }
+
+    let mut rows = vec![["ID".to_string(), "NAME".to_string(), "TYPE".to_string(), "ADDRESSES".to_string(), "FINGERPRINT".to_string()]];
+    for d in &devices {
+        rows.push([
+            d.device.id.clone(),
+            d.device.name.clone(),
+            d.device.r#type.clone(),
+            d.routes.iter().map(|r| r.addr.to_string()).collect::<Vec<_>>().join(","),
+            d.fingerprint.as_deref().map(|fp| fp[..16.min(fp.len())].to_string()).unwrap_or_else(|| "-".to_string()),
+        ]);
+    }
+    let widths: Vec<usize> = (0..5).map(|i| rows.iter().map(|row| row[i].chars().count()).max().unwrap_or(0)).collect();
+    for row in &rows {
+        let line: Vec<String> = row.iter().zip(&widths).map(|(cell, width)| format!("{:<width$}", cell, width = width)).collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+    if devices.is_empty() {
+        eprintln!("no device answered within {}s", options.timeout.as_secs());
+    }
+    Ok(())
+}
+
+/// send files to a device found by id, name or address
+async fn send(args: &[String]) -> io::Result<()> {
+    let options = parse_options(args)?;
+    if options.args.len() < 2 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, USAGE));
+    }
+    let target = &options.args[0];
+    let files: Vec<PathBuf> = options.args[1..].iter().map(PathBuf::from).collect();
+    for file in &files {
+        if !file.is_file() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is no file", file.display())));
+        }
+    }
+
+    let ctx = Context::load(&options)?;
+    // a port given with the address is used as is, otherwise the one the device announces
+    let explicit: Option<SocketAddr> = target.parse().ok();
+    let ip: Option<IpAddr> = explicit.map(|addr| addr.ip()).or_else(|| target.parse().ok());
+    let probe: Vec<IpAddr> = ip.into_iter().collect();
+    let devices = match ctx.discover(options.timeout, &probe).await {
+        Ok(devices) => devices,
+        // e.g. another program holds the discovery port, the address is enough
+        Err(e) if explicit.is_some() => {
+            eprintln!("no discovery: {}", e);
+            Vec::new()
+        },
+        Err(e) => return Err(e),
+    };
+    let mut device = match ip {
+        // what the device announced is used if it answers, the address as given either way
+        Some(ip) => {
+            let found = devices.into_iter().find(|d| d.routes.iter().any(|r| r.addr.ip() == ip));
+            let announced = found.as_ref()
+                .and_then(|d| d.routes.iter().find(|r| r.addr.ip() == ip))
+                .map(|r| r.addr);
+            let addr = explicit.or(announced).unwrap_or_else(|| SocketAddr::new(ip, ctx.config.receive.peer_port()));
+            let mut device = found
+                .unwrap_or_else(|| RemoteTcpDevice::unreachable(&[addr], Device {
+                    name: addr.to_string(),
+                    r#type: "unknown".to_string(),
+                    id: addr.to_string(),
+                }));
+            device.addr = addr;
+            device.routes = RemoteTcpDevice::unreachable(&[addr], device.device.clone()).routes;
+            device
+        },
+        None => device::pick(&devices, target)?.clone(),
+    };
+    if let Some(fingerprint) = ctx.pins.lock().unwrap().get(&device.device.id) {
+        device.fingerprint = Some(fingerprint.clone());
+    }
+    if !device.compatible {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, format!("{} speaks an incompatible protocol version {}", device.device.name, device.version)));
+    }
+
+    let encrypt = device.supports(CAP_ENCRYPTION);
+    let mut connected = None;
+    for route in device.routes_by_preference(|addr| ctx.is_local(addr)) {
+        let mut c = match ClientConnector::connect(route.addr).await {
+            Ok(c) => c.with_buffer_size(ctx.config.transfer.buffer_size),
+            Err(e) => {
+                eprintln!("connect to {} failed: {}", route.addr, e);
+                continue;
+            },
+        };
+        // another host may have the address now, try the next one
+        let peer_key = match c.send_public_key(&ctx.identity.public_key, encrypt).await {
+            Ok(key) => key,
+            Err(e) => {
+                eprintln!("key exchange on {} failed: {}", route.addr, e);
+                continue;
+            },
+        };
+        if device.fingerprint.as_ref().is_some_and(|expected| &identity::fingerprint(&peer_key) != expected) {
+            eprintln!("public key on {} does not match the announced fingerprint", route.addr);
+            continue;
+        }
+        connected = Some((c, peer_key));
+        break;
+    }
+    let (mut conn, peer_key) = connected.ok_or_else(|| io::Error::new(io::ErrorKind::ConnectionRefused, format!("can't reach {} with its key", device.device.name)))?;
+
+    if encrypt {
+        conn = conn.secure(&peer_key).await?;
+    }
+
+    eprintln!("sending {} files to {}{}", files.len(), device.device.name, if encrypt { ", encrypted" } else { "" });
+    let mut last = None;
+    conn.send_files_with_progress(&files, |sent, total| {
+        let percent = (sent * 100).checked_div(total).unwrap_or(100);
+        if last != Some(percent) {
+            last = Some(percent);
+            draw_progress(sent, total);
+        }
+    }).await.inspect_err(|_| eprintln!())?;
+    eprintln!();
+    Ok(())
+}
+
+/// `[=========>         ]  45%  1.2/2.6 MiB`, redrawn in place on stderr
+fn draw_progress(sent: u64, total: u64) {
+    let ratio = if total == 0 { 1.0 } else { sent as f64 / total as f64 };
+    let done = ((ratio * BAR_WIDTH as f64) as usize).min(BAR_WIDTH);
+    let mib = |bytes: u64| bytes as f64 / (1024.0 * 1024.0);
+    eprint!("\r[{}{}] {:>3}%  {:.1}/{:.1} MiB", "=".repeat(done), " ".repeat(BAR_WIDTH - done), (ratio * 100.0) as u32, mib(sent), mib(total));
+}
+
+/// announce this device, receive one transfer and exit
+async fn receive(args: &[String]) -> io::Result<()> {
+    let options = parse_options(args)?;
+    let mut ctx = Context::load(&options)?;
+    if let Some(dir) = options.args.first() {
+        ctx.config.receive.download_dir = Some(dir.into());
+        ctx.config.validate().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    }
+
+    let receiver = Receiver::new(ctx.config.receive.clone(), ctx.identity.private_key.clone(), ctx.pins.clone(), Transfers::default(), Subscribers::default())
+        .with_buffer_size(ctx.config.transfer.buffer_size);
+    let (done_tx, mut done) = tokio::sync::mpsc::channel(1);
+    let mut accepters = Vec::new();
+    // bound first, discovery announces the ports they got
+    let mut ports = HashMap::new();
+    for (_, network) in interfaces::selected_networks(&ctx.config.interfaces) {
+        let accepter = Accepter::new(&network.ip().to_string(), ctx.config.receive.port).await?;
+        let port = accepter.port()?;
+        ports.insert(network, port);
+        let key = ctx.identity.public_key.clone();
+        let receiver = receiver.clone();
+        let done_tx = done_tx.clone();
+        accepters.push(tokio::spawn(async move {
+            loop {
+                let (mut stream, addr) = match accepter.incoming().await {
+                    Ok(incoming) => incoming,
+                    Err(e) => {
+                        info!("accept failed: {}", e);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    },
+                };
+                let key = key.clone();
+                let receiver = receiver.clone();
+                let done_tx = done_tx.clone();
+                // a slow or failing sender doesn't hold up the others
+                tokio::spawn(async move {
+                    let result = match Accepter::handshake(&mut stream, &key).await {
+                        Ok(handshake) => receiver.receive(stream, &addr.to_string(), handshake).await,
+                        Err(e) => Err(e),
+                    };
+                    match result {
+                        Ok(Some(list)) => { let _ = done_tx.send(list).await; },
+                        Ok(None) => eprintln!("declined a transfer from {}", addr),
+                        Err(e) => eprintln!("transfer from {} failed: {}", addr, e),
+                    }
+                });
+            }
+        }));
+        eprintln!("waiting on {}:{}", network.ip(), port);
+    }
+    drop(done_tx);
+
+    let (tx, mut found) = tokio::sync::mpsc::channel(32);
+    // without discovery senders need the address printed above
+    let _services = ctx.start_discovery(tx, &[], &ports).await.unwrap_or_else(|e| {
+        eprintln!("not announced: {}", e);
+        Vec::new()
+    });
+    // nobody looks at the devices found, just keep the channel flowing
+    tokio::spawn(async move { while found.recv().await.is_some() {} });
+
+    let result = match done.recv().await {
+        Some(list) => {
+            for file in &list.files {
+                println!("{}\t{}", file.name, file.size);
+            }
+            Ok(())
+        },
+        None => Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "no accepter running")),
+    };
+    for accepter in accepters {
+        accepter.abort();
+    }
+    result
+}
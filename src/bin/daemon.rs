@@ -0,0 +1,73 @@
+use tokio::io;
+use log::info;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
+use simp_drop::Controller;
+use simp_drop::controller::command::{Command, Event};
+use simp_drop::config::Config;
+use simp_drop::signals::Signals;
+
+/// how long the controller gets to stop after a signal, more than its transfer grace
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Receive files without a window: discovery, the accepters and the relay connection
+/// run until SIGINT or SIGTERM. `rsdrop-daemon [--config <file>] [download dir]`, the rest
+/// comes from the config file, see `[receive]` for the accept rules.
+#[tokio::main]
+async fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    if let Err(e) = run().await {
+        eprintln!("rsdrop-daemon: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run() -> io::Result<()> {
+    // before anything slow, e.g. creating the identity, so an early signal isn't fatal
+    let mut signals = Signals::new()?;
+    let mut args = std::env::args().skip(1).peekable();
+    let config_path = match args.peek().map(String::as_str) {
+        Some("--config") => {
+            args.next();
+            Some(args.next().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "--config needs a file"))?)
+        },
+        _ => None,
+    };
+    let mut config = Config::load_at(config_path.as_deref().map(std::path::Path::new))?;
+    if let Some(dir) = args.next() {
+        config.receive.download_dir = Some(dir.into());
+        config.validate().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    }
+    let dir = config.receive.download_dir.clone().unwrap_or_else(|| ".".into());
+    info!("receive into {}, accept {:?}, {} allowed keys", dir.display(), config.receive.accept, config.receive.allow.len());
+
+    let mut controller = Controller::new(config);
+    let (commands, mut events) = controller.gen_ctx();
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                // the transfer log is enough
+                Ok(Event::TransferProgress { .. }) => {},
+                Ok(event) => info!("{}", event),
+                Err(RecvError::Lagged(missed)) => info!("{} events not logged", missed),
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+
+    let run = controller.start_loop();
+    tokio::pin!(run);
+    tokio::select! {
+        result = &mut run => return result,
+        signal = signals.recv() => info!("{}, shutting down", signal),
+    }
+    // the loop says goodbye, lets running transfers finish for a moment and saves the known peers
+    let _ = commands.send(Command::Shutdown).await;
+    drop(commands);
+    match tokio::time::timeout(SHUTDOWN_TIMEOUT, run).await {
+        Ok(result) => result?,
+        Err(_) => info!("controller didn't stop in {:?}", SHUTDOWN_TIMEOUT),
+    }
+    Ok(())
+}
@@ -0,0 +1,166 @@
+use tokio::net::{TcpListener, TcpStream};
+use tokio::io;
+use tokio::sync::{mpsc, oneshot};
+use log::{debug, info};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use simp_drop::relay_proto::{RelayMessage, read_message, write_message, RELAY_PORT};
+
+/// how long a sender waits for the receiver to open its data connection
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Forwards discovery requests between registered instances and pipes data
+/// connections between them. Transfers are encrypted end to end, the relay
+/// only sees ciphertext.
+#[derive(Default)]
+struct Relay {
+    /// control connections by device id
+    clients: Mutex<HashMap<String, Client>>,
+    /// senders waiting for the receiver to accept, by session
+    pending: Mutex<HashMap<String, oneshot::Sender<TcpStream>>>,
+}
+
+/// a registered instance
+struct Client {
+    tx: mpsc::Sender<RelayMessage>,
+    /// where the control connection comes from, data connections in its name must too
+    ip: IpAddr,
+}
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let listen = std::env::args().nth(1).unwrap_or_else(|| format!("0.0.0.0:{}", RELAY_PORT));
+    let listener = TcpListener::bind(&listen).await?;
+    info!("relay listen on {}", listen);
+
+    let relay = Arc::new(Relay::default());
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        debug!("connection from {}", addr);
+        let relay = relay.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(relay, stream).await {
+                debug!("connection from {} closed: {}", addr, e);
+            }
+        });
+    }
+}
+
+async fn handle(relay: Arc<Relay>, mut stream: TcpStream) -> io::Result<()> {
+    match read_message(&mut stream).await? {
+        RelayMessage::Register { id } => control(relay, stream, id).await,
+        RelayMessage::Connect { from, to, session } => connect(relay, stream, from, to, session).await,
+        RelayMessage::Accept { session } => {
+            let waiting = relay.pending.lock().unwrap().remove(&session);
+            match waiting {
+                Some(tx) => {
+                    let _ = tx.send(stream);
+                    Ok(())
+                },
+                None => write_message(&mut stream, &RelayMessage::Error { message: "unknown session".to_string() }).await,
+            }
+        },
+        _ => write_message(&mut stream, &RelayMessage::Error { message: "unexpected message".to_string() }).await,
+    }
+}
+
+/// the control connection of a registered instance
+async fn control(relay: Arc<Relay>, mut stream: TcpStream, id: String) -> io::Result<()> {
+    let ip = stream.peer_addr()?.ip();
+    let (tx, mut rx) = mpsc::channel(32);
+    let taken = {
+        let mut clients = relay.clients.lock().unwrap();
+        // first come, first served: nobody takes over the id, and its transfers, while it is connected
+        match clients.get(&id) {
+            Some(client) if !client.tx.is_closed() => true,
+            _ => {
+                clients.insert(id.clone(), Client { tx: tx.clone(), ip });
+                false
+            },
+        }
+    };
+    if taken {
+        info!("refuse {} from {}: already registered", id, ip);
+        return write_message(&mut stream, &RelayMessage::Error { message: format!("{} is already registered", id) }).await;
+    }
+    info!("register {} from {}", id, ip);
+    let (mut reader, mut writer) = stream.into_split();
+    tx.send(RelayMessage::Registered).await.map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "closed"))?;
+
+    let writer_task = tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            if write_message(&mut writer, &msg).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let result = loop {
+        let msg = match read_message(&mut reader).await {
+            Ok(msg) => msg,
+            Err(e) => break Err(e),
+        };
+        if let RelayMessage::Discovery { to, data, .. } = msg {
+            let targets: Vec<mpsc::Sender<RelayMessage>> = {
+                let clients = relay.clients.lock().unwrap();
+                match &to {
+                    Some(to) => clients.get(to).map(|c| c.tx.clone()).into_iter().collect(),
+                    None => clients.iter().filter(|(k, _)| **k != id).map(|(_, c)| c.tx.clone()).collect(),
+                }
+            };
+            let forward = RelayMessage::Discovery { from: Some(id.clone()), to, data };
+            for target in targets {
+                // a slow client misses announcements rather than blocking everyone
+                let _ = target.try_send(forward.clone());
+            }
+        }
+    };
+
+    {
+        let mut clients = relay.clients.lock().unwrap();
+        if clients.get(&id).is_some_and(|c| c.tx.same_channel(&tx)) {
+            clients.remove(&id);
+        }
+    }
+    writer_task.abort();
+    info!("unregister {}", id);
+    result
+}
+
+/// a sender asks for a data connection to `to`
+async fn connect(relay: Arc<Relay>, mut stream: TcpStream, from: String, to: String, session: String) -> io::Result<()> {
+    // only a registered sender, from where it registered, may connect in its name
+    let ip = stream.peer_addr()?.ip();
+    let known = relay.clients.lock().unwrap().get(&from).is_some_and(|c| c.ip == ip);
+    if !known {
+        return write_message(&mut stream, &RelayMessage::Error { message: format!("{} is not registered from {}", from, ip) }).await;
+    }
+    let target = relay.clients.lock().unwrap().get(&to).map(|c| c.tx.clone());
+    let target = match target {
+        Some(target) => target,
+        None => return write_message(&mut stream, &RelayMessage::Error { message: format!("{} is not registered", to) }).await,
+    };
+
+    let (tx, rx) = oneshot::channel();
+    relay.pending.lock().unwrap().insert(session.clone(), tx);
+    let _ = target.send(RelayMessage::Incoming { from: from.clone(), session: session.clone() }).await;
+
+    let mut peer = match tokio::time::timeout(ACCEPT_TIMEOUT, rx).await {
+        Ok(Ok(peer)) => peer,
+        _ => {
+            relay.pending.lock().unwrap().remove(&session);
+            return write_message(&mut stream, &RelayMessage::Error { message: format!("{} did not accept", to) }).await;
+        },
+    };
+
+    write_message(&mut stream, &RelayMessage::Connected).await?;
+    write_message(&mut peer, &RelayMessage::Connected).await?;
+    info!("relay {} -> {}", from, to);
+    let (sent, received) = io::copy_bidirectional(&mut stream, &mut peer).await?;
+    info!("relay {} -> {} done, {} bytes sent, {} bytes back", from, to, sent, received);
+    Ok(())
+}
@@ -1 +1,2 @@
-pub mod fusion_label;
\ No newline at end of file
+pub mod fusion_label;
+pub mod qr_code;
\ No newline at end of file
@@ -0,0 +1,55 @@
+use eframe::egui::{self, Color32, Rect, Response, Sense, Ui, Widget};
+
+/// modules of white border a scanner needs around the code
+const QUIET_ZONE: usize = 4;
+
+/// Draws a qr code, dark modules on a white background.
+///
+/// ```ignore
+/// let code = qrcode::QrCode::new("drop://...").unwrap();
+/// ui.add(QrCodeView::new(&code).module_size(4.0));
+/// ```
+#[must_use = "You should put this widget in an ui with `ui.add(widget);`"]
+pub struct QrCodeView<'a> {
+    code: &'a qrcode::QrCode,
+    module_size: f32,
+}
+
+impl<'a> QrCodeView<'a> {
+    pub fn new(code: &'a qrcode::QrCode) -> Self {
+        Self {
+            code,
+            module_size: 4.0,
+        }
+    }
+
+    /// side length of one module in points
+    pub fn module_size(mut self, module_size: f32) -> Self {
+        self.module_size = module_size;
+        self
+    }
+}
+
+impl Widget for QrCodeView<'_> {
+    fn ui(self, ui: &mut Ui) -> Response {
+        let width = self.code.width();
+        let side = (width + 2 * QUIET_ZONE) as f32 * self.module_size;
+        let (rect, response) = ui.allocate_exact_size(egui::vec2(side, side), Sense::hover());
+
+        if ui.is_rect_visible(rect) {
+            let painter = ui.painter();
+            painter.rect_filled(rect, 0.0, Color32::WHITE);
+            let module = egui::vec2(self.module_size, self.module_size);
+            for (i, color) in self.code.to_colors().iter().enumerate() {
+                if *color != qrcode::Color::Dark {
+                    continue;
+                }
+                let x = (i % width + QUIET_ZONE) as f32 * self.module_size;
+                let y = (i / width + QUIET_ZONE) as f32 * self.module_size;
+                painter.rect_filled(Rect::from_min_size(rect.min + egui::vec2(x, y), module), 0.0, Color32::BLACK);
+            }
+        }
+
+        response
+    }
+}
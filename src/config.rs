@@ -0,0 +1,489 @@
+use serde::{Serialize, Deserialize};
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::io;
+use pnet::ipnetwork::Ipv4Network;
+
+use crate::controller::accepter::TCP_ACCEPTER_PORT;
+use crate::controller::discoverer::{MULTICAST_IP, UDP_PORT};
+use crate::utils;
+
+const CONFIG_DIR: &str = "rsdrop";
+const CONFIG_FILE: &str = "config.toml";
+/// bytes read and written at once when sending or receiving a file
+pub const DEFAULT_BUFFER_SIZE: usize = 10240;
+const MIN_BUFFER_SIZE: usize = 512;
+const MAX_BUFFER_SIZE: usize = 16 * 1024 * 1024;
+/// longest device name, it has to fit in a discovery datagram
+const MAX_NAME_LEN: usize = 64;
+
+/// path of the config file, instead of the one in the config directory
+pub const ENV_CONFIG: &str = "RSDROP_CONFIG";
+pub const ENV_DEVICE_NAME: &str = "RSDROP_DEVICE_NAME";
+pub const ENV_DOWNLOAD_DIR: &str = "RSDROP_DOWNLOAD_DIR";
+/// `all`, `known`, `allowed` or `ask`
+pub const ENV_ACCEPT: &str = "RSDROP_ACCEPT";
+/// tcp port of the accepter
+pub const ENV_PORT: &str = "RSDROP_PORT";
+pub const ENV_DISCOVERY_PORT: &str = "RSDROP_DISCOVERY_PORT";
+
+#[derive(Clone,Debug,Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub device: DeviceConfig,
+    pub discovery: DiscoveryConfig,
+    pub interfaces: InterfaceConfig,
+    /// peers probed directly, e.g. in another vlan
+    pub peers: Vec<StaticPeer>,
+    pub relay: RelayConfig,
+    pub receive: ReceiveConfig,
+    pub transfer: TransferConfig,
+    pub control: ControlConfig,
+}
+
+#[derive(Clone,Debug,Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeviceConfig {
+    /// shown to other devices, the host name when unset
+    pub name: Option<String>,
+}
+
+#[derive(Clone,Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DiscoveryConfig {
+    /// send discovery to the subnet broadcast address as well as multicast
+    pub broadcast: BroadcastMode,
+    /// seconds to wait for a multicast response before falling back to broadcast
+    pub broadcast_fallback_secs: u64,
+    /// seconds between two announcements of this device
+    pub announce_secs: u64,
+    /// seconds between two probes of the static peers
+    pub static_probe_secs: u64,
+    /// discovery requests per second accepted from one address
+    pub rate_limit: u32,
+    /// devices kept in the list, announcements of further devices are dropped
+    pub max_devices: usize,
+    /// udp port discovery requests are sent to and received on
+    pub port: u16,
+    pub multicast_ip: Ipv4Addr,
+    /// routers a multicast request may pass
+    pub multicast_ttl: u32,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            broadcast: BroadcastMode::Auto,
+            broadcast_fallback_secs: 3,
+            announce_secs: 30,
+            static_probe_secs: 30,
+            rate_limit: 5,
+            max_devices: 256,
+            port: UDP_PORT,
+            multicast_ip: MULTICAST_IP,
+            multicast_ttl: 50,
+        }
+    }
+}
+
+#[derive(Clone,Debug, Serialize, Deserialize)]
+pub struct StaticPeer {
+    /// host name or ip, optionally with the tcp port of the receiver
+    pub address: String,
+    /// shown until the peer answers with its own name
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+#[derive(Clone,Copy,Debug,PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BroadcastMode {
+    /// only broadcast when no multicast response arrives
+    Auto,
+    Always,
+    Never,
+}
+
+/// which interface addresses discovery and the accepter run on
+#[derive(Clone,Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InterfaceConfig {
+    /// interface name globs to use, empty means all
+    pub include: Vec<String>,
+    /// interface name globs to skip
+    pub exclude: Vec<String>,
+    /// networks (CIDR) an address must be in, empty means all
+    pub include_cidr: Vec<String>,
+    /// networks (CIDR) whose addresses are skipped
+    pub exclude_cidr: Vec<String>,
+    pub loopback: bool,
+}
+
+impl Default for InterfaceConfig {
+    fn default() -> Self {
+        Self {
+            include: Vec::new(),
+            exclude: ["*docker*", "veth*", "br-*", "virbr*", "tailscale*", "wg*"].iter().map(|s| s.to_string()).collect(),
+            include_cidr: Vec::new(),
+            exclude_cidr: Vec::new(),
+            loopback: false,
+        }
+    }
+}
+
+#[derive(Clone,Debug,Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RelayConfig {
+    /// `host:port` of a rsdrop-relay server, reaches devices in other subnets
+    pub address: Option<String>,
+}
+
+#[derive(Clone,Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TransferConfig {
+    /// bytes read and written at once
+    pub buffer_size: usize,
+}
+
+impl Default for TransferConfig {
+    fn default() -> Self {
+        Self {
+            buffer_size: DEFAULT_BUFFER_SIZE,
+        }
+    }
+}
+
+/// the json-rpc api on a unix socket that other tools drive a running instance with
+#[derive(Clone,Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ControlConfig {
+    pub enabled: bool,
+    /// socket path, `$XDG_RUNTIME_DIR/rsdrop.sock` when unset
+    pub socket: Option<PathBuf>,
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            socket: None,
+        }
+    }
+}
+
+/// what incoming transfers are accepted and where they go
+#[derive(Clone,Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReceiveConfig {
+    /// tcp port the accepter listens on, 0 for a free one announced through discovery
+    pub port: u16,
+    /// where received files are written, the working directory when unset
+    pub download_dir: Option<PathBuf>,
+    pub accept: AcceptPolicy,
+    /// sender key fingerprints always accepted
+    pub allow: Vec<String>,
+    /// largest transfer accepted in bytes, 0 for no limit
+    pub max_size: u64,
+}
+
+impl Default for ReceiveConfig {
+    fn default() -> Self {
+        Self {
+            port: TCP_ACCEPTER_PORT,
+            download_dir: None,
+            accept: AcceptPolicy::All,
+            allow: Vec::new(),
+            max_size: 0,
+        }
+    }
+}
+
+impl ReceiveConfig {
+    /// tcp port assumed for a peer given without one, ours unless the system picks it
+    pub fn peer_port(&self) -> u16 {
+        if self.port == 0 { TCP_ACCEPTER_PORT } else { self.port }
+    }
+
+    /// whether to take `size` bytes from the sender with `fingerprint`,
+    /// `pinned` when it is the key of a pinned device
+    pub fn accepts(&self, fingerprint: &str, pinned: bool, size: u64) -> bool {
+        if self.max_size != 0 && size > self.max_size {
+            return false;
+        }
+        if self.allow.iter().any(|fp| fp.eq_ignore_ascii_case(fingerprint)) {
+            return true;
+        }
+        match self.accept {
+            AcceptPolicy::All => true,
+            AcceptPolicy::Known => pinned,
+            AcceptPolicy::Allowed | AcceptPolicy::Ask => false,
+        }
+    }
+
+    /// whether a transfer of `size` bytes that isn't accepted right away is offered to the user
+    pub fn asks(&self, size: u64) -> bool {
+        self.accept == AcceptPolicy::Ask && (self.max_size == 0 || size <= self.max_size)
+    }
+}
+
+#[derive(Clone,Copy,Debug,PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AcceptPolicy {
+    /// every sender
+    All,
+    /// pinned devices and the `allow` list
+    Known,
+    /// only the `allow` list
+    Allowed,
+    /// the `allow` list, everyone else is asked in the front end
+    Ask,
+}
+
+impl std::str::FromStr for AcceptPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(AcceptPolicy::All),
+            "known" => Ok(AcceptPolicy::Known),
+            "allowed" => Ok(AcceptPolicy::Allowed),
+            "ask" => Ok(AcceptPolicy::Ask),
+            other => Err(format!("unknown accept policy \"{}\", use all, known, allowed or ask", other)),
+        }
+    }
+}
+
+impl Config {
+    /// `$XDG_CONFIG_HOME/rsdrop/config.toml`, or `~/.config/rsdrop/config.toml`
+    pub fn path() -> Option<PathBuf> {
+        let base = match std::env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+        };
+        Some(base.join(CONFIG_DIR).join(CONFIG_FILE))
+    }
+
+    /// load the config file, a missing file gives the default config
+    pub fn load() -> io::Result<Self> {
+        Self::load_at(None)
+    }
+
+    /// Load the config file at `path`, `$RSDROP_CONFIG` or the default path, then apply
+    /// the `RSDROP_*` environment variables and validate the result. A file given
+    /// explicitly must exist.
+    pub fn load_at(path: Option<&Path>) -> io::Result<Self> {
+        let given = path.map(Path::to_path_buf)
+            .or_else(|| std::env::var_os(ENV_CONFIG).filter(|p| !p.is_empty()).map(PathBuf::from));
+        let (mut config, source) = match (given, Self::path()) {
+            (Some(path), _) if !path.exists() => {
+                return Err(io::Error::new(io::ErrorKind::NotFound, format!("config file {} does not exist", path.display())));
+            },
+            (Some(path), _) => (Self::load_from(&path)?, Some(path)),
+            (None, Some(path)) if path.exists() => (Self::load_from(&path)?, Some(path)),
+            _ => (Self::default(), None),
+        };
+        // an override may fix a value of the file, so only the result counts
+        config.apply_env()?;
+        config.validate().map_err(|e| match source {
+            Some(path) => io::Error::new(io::ErrorKind::InvalidData, format!("invalid config {}: {}", path.display(), e)),
+            None => io::Error::new(io::ErrorKind::InvalidData, format!("invalid config: {}", e)),
+        })?;
+        Ok(config)
+    }
+
+    /// parse the file at `path`, `validate` is up to the caller
+    pub fn load_from(path: &Path) -> io::Result<Self> {
+        let data = std::fs::read_to_string(path)?;
+        toml::from_str(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+    }
+
+    /// override single values with the `RSDROP_*` environment variables
+    fn apply_env(&mut self) -> io::Result<()> {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        let invalid = |name: &str, msg: String| io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", name, msg));
+        if let Some(name) = var(ENV_DEVICE_NAME) {
+            self.device.name = Some(name);
+        }
+        if let Some(dir) = var(ENV_DOWNLOAD_DIR) {
+            self.receive.download_dir = Some(dir.into());
+        }
+        if let Some(accept) = var(ENV_ACCEPT) {
+            self.receive.accept = accept.parse().map_err(|e| invalid(ENV_ACCEPT, e))?;
+        }
+        if let Some(port) = var(ENV_PORT) {
+            self.receive.port = port.parse().map_err(|_| invalid(ENV_PORT, format!("invalid port \"{}\"", port)))?;
+        }
+        if let Some(port) = var(ENV_DISCOVERY_PORT) {
+            self.discovery.port = port.parse().map_err(|_| invalid(ENV_DISCOVERY_PORT, format!("invalid port \"{}\"", port)))?;
+        }
+        Ok(())
+    }
+
+    /// the name other devices see
+    pub fn device_name(&self) -> String {
+        self.device.name.clone().unwrap_or_else(utils::hostname)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(name) = &self.device.name {
+            if name.trim().is_empty() {
+                return Err("device: name must not be empty".to_string());
+            }
+            if name.len() > MAX_NAME_LEN {
+                return Err(format!("device: name is longer than {} bytes", MAX_NAME_LEN));
+            }
+        }
+        if self.discovery.port == 0 {
+            return Err("discovery: port must not be 0".to_string());
+        }
+        if !self.discovery.multicast_ip.is_multicast() {
+            return Err(format!("discovery: multicast_ip {} is no multicast address, use one in 224.0.0.0/4", self.discovery.multicast_ip));
+        }
+        if !(1..=255).contains(&self.discovery.multicast_ttl) {
+            return Err("discovery: multicast_ttl must be between 1 and 255".to_string());
+        }
+        if let Some(dir) = &self.receive.download_dir {
+            if !dir.is_dir() {
+                return Err(format!("receive: download_dir {} is no directory", dir.display()));
+            }
+        }
+        if !(MIN_BUFFER_SIZE..=MAX_BUFFER_SIZE).contains(&self.transfer.buffer_size) {
+            return Err(format!("transfer: buffer_size must be between {} and {}", MIN_BUFFER_SIZE, MAX_BUFFER_SIZE));
+        }
+        for cidr in self.interfaces.include_cidr.iter().chain(self.interfaces.exclude_cidr.iter()) {
+            cidr.parse::<Ipv4Network>().map_err(|e| format!("interfaces: invalid network \"{}\": {}", cidr, e))?;
+        }
+        if self.discovery.announce_secs == 0 {
+            return Err("discovery: announce_secs must be greater than 0".to_string());
+        }
+        if self.discovery.static_probe_secs == 0 {
+            return Err("discovery: static_probe_secs must be greater than 0".to_string());
+        }
+        if self.discovery.rate_limit == 0 {
+            return Err("discovery: rate_limit must be greater than 0".to_string());
+        }
+        if self.discovery.max_devices == 0 {
+            return Err("discovery: max_devices must be greater than 0".to_string());
+        }
+        for fp in &self.receive.allow {
+            if fp.len() != 64 || !fp.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(format!("receive: allow entry \"{}\" is no sha256 fingerprint", fp));
+            }
+        }
+        for peer in &self.peers {
+            if peer.address.trim().is_empty() {
+                return Err("peers: address must not be empty".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FP: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn validate_default() {
+        assert_eq!(Config::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn validate_invalid() {
+        let invalid: [fn(&mut Config); 9] = [
+            |c| c.device.name = Some(" ".to_string()),
+            |c| c.device.name = Some("x".repeat(MAX_NAME_LEN + 1)),
+            |c| c.discovery.port = 0,
+            |c| c.discovery.announce_secs = 0,
+            |c| c.discovery.multicast_ip = Ipv4Addr::new(10, 0, 0, 1),
+            |c| c.transfer.buffer_size = MIN_BUFFER_SIZE - 1,
+            |c| c.interfaces.exclude_cidr = vec!["10.0.0.0/40".to_string()],
+            |c| c.receive.allow = vec![FP[1..].to_string()],
+            |c| c.receive.download_dir = Some("/nonexistent/rsdrop".into()),
+        ];
+        for (i, change) in invalid.iter().enumerate() {
+            let mut config = Config::default();
+            change(&mut config);
+            assert!(config.validate().is_err(), "change {} passed", i);
+        }
+        let mut config = Config::default();
+        config.receive.allow = vec![FP.to_uppercase()];
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn receive_accepts() {
+        let mut receive = ReceiveConfig::default();
+        assert!(receive.accepts("aa", false, u64::MAX));
+        receive.max_size = 100;
+        assert!(receive.accepts("aa", false, 100));
+        assert!(!receive.accepts("aa", false, 101));
+        receive.accept = AcceptPolicy::Known;
+        assert!(receive.accepts("aa", true, 1));
+        assert!(!receive.accepts("aa", false, 1));
+        receive.accept = AcceptPolicy::Allowed;
+        receive.allow = vec![FP.to_string()];
+        assert!(!receive.accepts("aa", true, 1));
+        assert!(receive.accepts(&FP.to_uppercase(), false, 1));
+        // the size limit holds for allowed senders too
+        assert!(!receive.accepts(FP, false, 101));
+        receive.accept = AcceptPolicy::Ask;
+        assert!(!receive.accepts("aa", true, 1));
+        assert!(receive.asks(100));
+        assert!(!receive.asks(101));
+    }
+
+    #[test]
+    fn default_excludes() {
+        let exclude = InterfaceConfig::default().exclude;
+        let excluded = |name: &str| exclude.iter().any(|p| utils::glob_match(p, name));
+        for name in ["docker0", "br-1a2b", "veth12ab", "virbr0", "tailscale0", "wg0"] {
+            assert!(excluded(name), "{} is used", name);
+        }
+        for name in ["eth0", "wlan0", "enp3s0", "lo"] {
+            assert!(!excluded(name), "{} is excluded", name);
+        }
+    }
+
+    #[test]
+    fn peer_port() {
+        let mut receive = ReceiveConfig::default();
+        assert_eq!(receive.peer_port(), TCP_ACCEPTER_PORT);
+        receive.port = 4000;
+        assert_eq!(receive.peer_port(), 4000);
+        receive.port = 0;
+        assert_eq!(receive.peer_port(), TCP_ACCEPTER_PORT);
+    }
+
+    /// the only test touching the `RSDROP_*` variables, tests run in parallel
+    #[test]
+    fn load_with_env() {
+        let path = std::env::temp_dir().join(format!("rsdrop-config-test-{}.toml", std::process::id()));
+        std::fs::write(&path, "[discovery]\nport = 0\n").unwrap();
+        std::env::set_var(ENV_PORT, "4000");
+        std::env::set_var(ENV_ACCEPT, "known");
+        // invalid in the file, fixed by the environment
+        std::env::set_var(ENV_DISCOVERY_PORT, "4001");
+        let config = Config::load_at(Some(&path));
+        std::env::set_var(ENV_DISCOVERY_PORT, "0");
+        let invalid = Config::load_at(Some(&path));
+        std::env::set_var(ENV_DISCOVERY_PORT, "port");
+        let unparsable = Config::load_at(Some(&path));
+        for name in [ENV_PORT, ENV_ACCEPT, ENV_DISCOVERY_PORT] {
+            std::env::remove_var(name);
+        }
+        let without_env = Config::load_at(Some(&path));
+        std::fs::remove_file(&path).unwrap();
+
+        let config = config.unwrap();
+        assert_eq!(config.receive.port, 4000);
+        assert_eq!(config.receive.accept, AcceptPolicy::Known);
+        assert_eq!(config.discovery.port, 4001);
+        assert_eq!(invalid.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(unparsable.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert!(without_env.is_err());
+        assert_eq!(Config::load_at(Some(&path)).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+}
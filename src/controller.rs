@@ -1,174 +1,749 @@
-mod accepter;
-mod connector;
-mod discoverer;
+pub mod accepter;
+pub mod command;
+pub mod connector;
+#[cfg(unix)]
+mod control;
+pub mod discoverer;
+mod flood;
+pub mod receiver;
+mod relay;
+mod rooms;
+pub mod secure;
+pub mod sender;
+pub mod signing;
+mod static_peers;
+pub mod subscriber;
+mod sweeper;
+pub mod transfers;
 
 use tokio::io;
 
-use rsa::{RsaPrivateKey, RsaPublicKey};
+use rsa::RsaPublicKey;
 
 use std::net::SocketAddr;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use pnet::ipnetwork::Ipv4Network;
 
 use log::{debug, info};
 
-use connector::ClientConnector;
-use crate::device::{self, Device, RemoteTcpDevice};
+use crate::error;
+use crate::config::Config;
+use crate::identity::{self, Identity};
+use crate::relay_proto::RELAY_PORT;
+use crate::share::ShareUri;
+use crate::interfaces;
+use crate::known_peers::KnownPeers;
+use crate::settings::{Settings, Visibility};
+use crate::device::{self, DeviceInfo, RemoteTcpDevice};
 use std::sync::{Arc,Mutex};
+use command::{Command, Event};
+use subscriber::{Subscriber, Subscribers};
+use transfers::{TransferState, Transfers};
+
+/// how often the network interfaces are checked for changes
+const INTERFACE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// how long running transfers may go on after `Command::Shutdown`
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(3);
 
-use eframe::egui;
+/// discovery and accepter running on one interface address
+struct InterfaceService {
+    name: String,
+    /// dropping it stops the discovery tasks, `None` while its port can't be bound
+    discovery: Option<discoverer::Discovery>,
+    add_tx: Option<tokio::sync::mpsc::Sender<String>>,
+    accepter: tokio::task::JoinHandle<()>,
+    /// the accepter listens on it, announced by the discovery of this interface
+    port: u16,
+}
 
+impl Drop for InterfaceService {
+    fn drop(&mut self) {
+        self.accepter.abort();
+    }
+}
+
+/// Runs discovery, the accepters and the relay connection of this device and keeps
+/// the list of devices found. Create it with [`Controller::new`], share the containers
+/// with the `set_*_container` methods and drive it with [`Controller::start_loop`].
 pub struct Controller {
-    private_key: RsaPrivateKey,
     public_key: RsaPublicKey,
     host: device::Device,
     devices: Arc<Mutex<Vec<RemoteTcpDevice>>>,
-    ui_ctx: egui::Context,
+    /// share uri of this device, follows the interface addresses
+    share: Arc<Mutex<String>>,
+    subscribers: Subscribers,
+    config: Config,
 
-    disc_txs: Vec<tokio::sync::mpsc::Sender<String>>,
+    services: HashMap<Ipv4Network, InterfaceService>,
+    known_peers: KnownPeers,
+    /// fingerprints of pinned devices by id, their announcements must be signed with that key
+    pins: Arc<Mutex<HashMap<String, String>>>,
+    announcer: signing::Announcer,
+    verifier: Arc<signing::Verifier>,
+    receiver: receiver::Receiver,
+    transfers: Transfers,
+    device_tx: Option<tokio::sync::mpsc::Sender<RemoteTcpDevice>>,
+    relay: Option<tokio::task::JoinHandle<()>>,
     //disc_rxs: tokio::sync::mpsc::Receiver<String>, // for device notify
 
-    //file_txs: tokio::sync::mpsc::Sender<String>, // for add_device
-    //file_rxs: Vec<tokio::sync::mpsc::Receiver<String>>,
-    tx: Option<tokio::sync::mpsc::Sender<String>>,
-    rx: Option<tokio::sync::mpsc::Receiver<String>>,
+    /// handed to the control api, weak so closing the front end still ends the loop
+    commands: Option<tokio::sync::mpsc::WeakSender<Command>>,
+    rx: Option<tokio::sync::mpsc::Receiver<Command>>,
+    #[cfg(unix)]
+    control: Option<control::Server>,
 }
 
 impl Controller {
-    pub fn new(ctx: egui::Context) -> Self {
-        let mut rng = rand::thread_rng();
-        let private_key = RsaPrivateKey::new(&mut rng, 2048).expect("failed to generate a key");
-        let public_key = RsaPublicKey::from(&private_key);
+    /// load or create the identity of this device, nothing is started yet
+    pub fn new(config: Config) -> Self {
+        let mut identity = Identity::load_or_create().unwrap_or_else(|e| {
+            info!("can't load identity: {}, use a temporary one", e);
+            Identity::generate()
+        });
+        identity.device.name = config.device_name();
         
         //let (tx1, rx1) = tokio::sync::mpsc::channel(10);
         //let (tx2, rx2) = tokio::sync::mpsc::channel(10);
+        let known_peers = KnownPeers::load();
+        let settings = Settings::load();
+        let pins = Arc::new(Mutex::new(known_peers.pins()));
+        let subscribers = Subscribers::default();
+        let transfers = Transfers::default();
         Self {
-            private_key,
-            public_key,
-            host: Device::default(),
+            announcer: signing::Announcer::new(identity.device.clone(), &identity.private_key, settings.visibility, settings.rooms),
+            verifier: Arc::new(signing::Verifier::new(pins.clone())),
+            receiver: receiver::Receiver::new(config.receive.clone(), identity.private_key, pins.clone(), transfers.clone(), subscribers.clone())
+                .with_buffer_size(config.transfer.buffer_size),
+            pins,
+            public_key: identity.public_key,
+            host: identity.device,
             devices: Arc::new(Mutex::new(Vec::<RemoteTcpDevice>::new())),
-            ui_ctx: ctx,
+            share: Arc::new(Mutex::new(String::new())),
+            subscribers,
+            transfers,
+            config,
             rx: None,
-            tx: None,
-            disc_txs: Vec::new(),
+            commands: None,
+            #[cfg(unix)]
+            control: None,
+            services: HashMap::new(),
+            known_peers,
+            device_tx: None,
+            relay: None,
         }
     }
 
-    pub fn gen_ctx(&mut self) -> (tokio::sync::mpsc::Sender<String>,tokio::sync::mpsc::Receiver<String>) {
-        let (tx1, rx1) = tokio::sync::mpsc::channel(10);
-        let (tx2, rx2) = tokio::sync::mpsc::channel(10);
-        self.tx = Some(tx1);
-        self.rx = Some(rx2);
-        (tx2,rx1)
+    /// channels to the front end: commands for the controller and the events it publishes.
+    /// the loop runs until every command sender is dropped.
+    pub fn gen_ctx(&mut self) -> (tokio::sync::mpsc::Sender<Command>,tokio::sync::broadcast::Receiver<Event>) {
+        let (tx, rx) = tokio::sync::mpsc::channel(10);
+        self.commands = Some(tx.downgrade());
+        self.rx = Some(rx);
+        (tx,self.subscribers.events())
+    }
+
+    /// another listener for the events, e.g. a second front end
+    pub fn events(&self) -> tokio::sync::broadcast::Receiver<Event> {
+        self.subscribers.events()
+    }
+
+    /// the running and recently finished transfers
+    pub fn transfers(&self) -> Transfers {
+        self.transfers.clone()
     }
 
+    /// tell `subscriber` whenever the shared containers change
+    pub fn subscribe(&mut self, subscriber: Arc<dyn Subscriber>) {
+        self.subscribers.add(subscriber);
+    }
+
+    /// the device list, kept up to date while the loop runs
     pub fn set_device_container(&mut self,devices: Arc<Mutex<Vec<RemoteTcpDevice>>>) {
         self.devices = devices;
     }
 
+    /// the share uri of this device
+    pub fn set_share_container(&mut self,share: Arc<Mutex<String>>) {
+        self.share = share;
+    }
+
+    /// who answers and announcements go to, read on every request
+    pub fn set_visibility_container(&mut self,visibility: Arc<Mutex<Visibility>>) {
+        self.announcer.set_visibility(visibility);
+    }
+
+    /// the joined rooms, read on every request
+    pub fn set_rooms_container(&mut self,rooms: Arc<Mutex<Vec<String>>>) {
+        self.announcer.set_rooms(rooms);
+    }
+
+    /// start all services and run until `Command::Shutdown` or the front end channel
+    /// closes, then shut them down again
     pub async fn start_loop(&mut self) -> io::Result<()> {
         debug!("controller start...");
         let rx = self.start_discovery_service().await?;
         debug!("controller start 1...");
-        //self.start_service().await?;
-        debug!("controller start 2...");
+        #[cfg(unix)]
+        self.start_control().await;
         self.sync_device_loop(rx).await?;
+        self.shutdown().await;
         Ok(())
     }
 
+    /// tell the other devices this one goes offline and stop everything `start_loop`
+    /// started. running transfers get [`SHUTDOWN_GRACE`] to finish, the rest is cancelled.
+    async fn shutdown(&mut self) {
+        info!("shutting down");
+        #[cfg(unix)]
+        {
+            self.control = None;
+        }
+        for discovery in self.services.values().filter_map(|s| s.discovery.as_ref()) {
+            discovery.goodbye(&self.announcer).await;
+        }
+        if let Some(relay) = self.relay.take() {
+            relay.abort();
+        }
+        // stops discovery and the accepters, connections already accepted go on
+        self.services.clear();
+        self.device_tx = None;
+
+        // nobody is left to answer offers
+        for transfer in self.transfers.list() {
+            if transfer.state == TransferState::Offered {
+                self.transfers.answer(transfer.id, false);
+            }
+        }
+        let deadline = tokio::time::Instant::now() + SHUTDOWN_GRACE;
+        while self.transfers.list().iter().any(|t| t.state.is_active()) && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        for transfer in self.transfers.list() {
+            if !transfer.state.is_active() {
+                continue;
+            }
+            if let Some(transfer) = self.transfers.cancel(transfer.id) {
+                info!("transfer {} cancelled on shutdown", transfer.id);
+                self.subscribers.publish(Event::TransferFinished { transfer });
+            }
+        }
+    }
+
+    /// serve the control api while the loop runs, see `[control]` in the config
+    #[cfg(unix)]
+    async fn start_control(&mut self) {
+        let commands = match &self.commands {
+            Some(commands) if self.config.control.enabled => commands.clone(),
+            _ => return,
+        };
+        let path = match self.config.control.socket.clone().or_else(control::default_socket) {
+            Some(path) => path,
+            None => {
+                info!("no place for the control socket, set control.socket");
+                return;
+            },
+        };
+        let api = control::Api {
+            commands,
+            devices: self.devices.clone(),
+            transfers: self.transfers.clone(),
+            subscribers: self.subscribers.clone(),
+        };
+        match control::serve(path, api).await {
+            Ok(server) => self.control = Some(server),
+            Err(e) => info!("control api not started: {}", e),
+        }
+    }
+
     pub async fn start_discovery_service(&mut self) -> io::Result<tokio::sync::mpsc::Receiver<device::RemoteTcpDevice>> {
-        let (tx, mut rx) = tokio::sync::mpsc::channel(32);
-        for interface in pnet::datalink::interfaces() {
-            if interface.is_up() && !interface.ips.is_empty() && !interface.is_loopback() && !interface.name.contains("docker") {
-                for ip in interface.ips {
-                    if ip.is_ipv4() {
-                        let discoverer = discoverer::Discovery::new(&ip.ip().to_string()).await?;
-                        let add_tx = discoverer.start(&self.host,tx.clone()).await?;
-                        self.disc_txs.push(add_tx);
-                    }
+        let (tx, rx) = tokio::sync::mpsc::channel(32);
+        self.device_tx = Some(tx);
+        for s in interfaces::select(&self.config.interfaces) {
+            let address = s.network.map(|n| n.to_string()).unwrap_or_default();
+            info!("interface {} {}: {}", s.name, address, s.reason);
+        }
+        self.refresh_interfaces().await;
+        self.probe_known_peers().await;
+        self.start_relay().await;
+        Ok(rx)
+    }
+
+    /// register at the relay server from the configuration, if any
+    async fn start_relay(&mut self) {
+        let address = match &self.config.relay.address {
+            Some(address) if address.contains(':') => address.clone(),
+            Some(address) => format!("{}:{}", address, RELAY_PORT),
+            None => return,
+        };
+        let relay = match tokio::net::lookup_host(&address).await.map(|mut addrs| addrs.next()) {
+            Ok(Some(relay)) => relay,
+            Ok(None) => {
+                info!("relay {} has no address", address);
+                return;
+            },
+            Err(e) => {
+                info!("can't resolve relay {}: {}", address, e);
+                return;
+            },
+        };
+        if let Some(tx) = self.device_tx.clone() {
+            self.relay = Some(tokio::spawn(relay::run(relay, self.announcer.clone(), self.verifier.clone(), self.public_key.clone(), self.receiver.clone(), tx)));
+        }
+    }
+
+    /// show the cached peers right away and ask them to announce themselves
+    async fn probe_known_peers(&self) {
+        let peers = self.known_peers.peers().to_vec();
+        {
+            let mut devices = self.devices.lock().unwrap();
+            for peer in &peers {
+                if !devices.iter().any(|d| d.device.id == peer.device.id) {
+                    devices.extend(peer.to_device());
                 }
             }
         }
-        Ok(rx)
+        self.subscribers.changed();
+
+        let disc_txs = self.disc_txs();
+        for addr in peers.iter().flat_map(|p| p.addrs.iter()) {
+            for tx in &disc_txs {
+                let _ = tx.send(addr.ip().to_string()).await;
+            }
+        }
     }
 
-    pub async fn start_service(&mut self) -> io::Result<()> {
-        for interface in pnet::datalink::interfaces() {
-            if interface.is_up() && !interface.ips.is_empty() && !interface.is_loopback() && !interface.name.contains("docker") {
-                for ip in interface.ips {
-                    if ip.is_ipv4() {
-                        let accepter = accepter::Accepter::new(&ip.ip().to_string()).await?;
-                        let key = self.public_key.clone();
-                        tokio::spawn(async move{
-                            loop {
-                                info!("start tcp server for receive file");
-                                let (mut stream,addr) = accepter.accept(&key).await.expect("has error");
-                                info!("accept addr {}",addr);
-                                tokio::spawn(async move {
-                                    accepter::Accepter::recv_files(&mut stream).await.expect("receive failed");
-                                });
-                            }
-                        });
+    /// start services on new interface addresses and stop the ones whose address went away
+    pub async fn refresh_interfaces(&mut self) {
+        let networks = interfaces::selected_networks(&self.config.interfaces);
+
+        let gone: Vec<Ipv4Network> = self.services.keys()
+            .filter(|network| !networks.iter().any(|(_, n)| n == *network))
+            .cloned()
+            .collect();
+        for network in gone {
+            if let Some(service) = self.services.remove(&network) {
+                info!("interface {} lost address {}, stop services", service.name, network);
+            }
+            // addresses in this network can't be reached anymore, nor devices without another one
+            let in_network = |addr: &SocketAddr| match addr.ip() {
+                std::net::IpAddr::V4(ip) => network.contains(ip),
+                _ => false,
+            };
+            let mut changed = false;
+            let mut lost = Vec::new();
+            self.devices.lock().unwrap().retain_mut(|d| {
+                if d.relayed || !d.routes.iter().any(|r| in_network(&r.addr)) {
+                    return true;
+                }
+                changed = true;
+                d.routes.retain(|r| !in_network(&r.addr));
+                match d.routes.first() {
+                    Some(route) => {
+                        if in_network(&d.addr) {
+                            d.addr = route.addr;
+                        }
+                        true
+                    },
+                    None => {
+                        lost.push((d.device.id.clone(), d.device.name.clone()));
+                        false
+                    },
+                }
+            });
+            for (id, name) in lost {
+                self.subscribers.publish(Event::DeviceLost { id, name });
+            }
+            if changed {
+                self.subscribers.changed();
+            }
+        }
+
+        for (name, network) in networks {
+            if let Some(service) = self.services.get(&network) {
+                if service.discovery.is_none() {
+                    let port = service.port;
+                    if let Some((discovery, add_tx)) = self.start_discovery(&name, &network, port).await {
+                        let service = self.services.get_mut(&network).expect("checked above");
+                        service.discovery = Some(discovery);
+                        service.add_tx = Some(add_tx);
                     }
                 }
+                continue;
+            }
+            match self.start_interface(&name, &network).await {
+                Ok(service) => {
+                    info!("interface {} has address {}, start services", name, network);
+                    self.services.insert(network, service);
+                },
+                // retried on the next refresh
+                Err(e) => info!("start services on {} {} failed: {}", name, network, e),
             }
         }
-        Ok(())
+
+        let uri = self.share_uri();
+        let updated = {
+            let mut share = self.share.lock().unwrap();
+            let updated = *share != uri;
+            *share = uri;
+            updated
+        };
+        // subscribers read the share uri when told, not while it is locked
+        if updated {
+            self.subscribers.changed();
+        }
+    }
+
+    async fn start_interface(&self, name: &str, network: &Ipv4Network) -> io::Result<InterfaceService> {
+        if self.device_tx.is_none() {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "discovery service is not started"));
+        }
+        let accepter = accepter::Accepter::new(&network.ip().to_string(), self.config.receive.port).await?;
+        let port = accepter.port()?;
+        let accepter = self.start_service(accepter);
+        info!("start tcp server for receive file on {}:{}", network.ip(), port);
+        // files sent to the address still arrive without discovery
+        let (discovery, add_tx) = self.start_discovery(name, network, port).await.unzip();
+        Ok(InterfaceService {
+            name: name.to_string(),
+            discovery,
+            add_tx,
+            accepter,
+            port,
+        })
+    }
+
+    /// announce the accepter on `port` of `network`, `None` when discovery can't start there.
+    /// it is tried again on the next interface refresh.
+    async fn start_discovery(&self, name: &str, network: &Ipv4Network, port: u16) -> Option<(discoverer::Discovery, tokio::sync::mpsc::Sender<String>)> {
+        let tx = self.device_tx.clone()?;
+        let mut discovery = match discoverer::Discovery::new(network, &self.config.discovery, port).await {
+            Ok(discovery) => discovery,
+            Err(e) => {
+                info!("no discovery on {} {}: {}", name, network, e);
+                return None;
+            },
+        };
+        match discovery.start(&self.announcer,self.verifier.clone(),tx).await {
+            Ok(add_tx) => Some((discovery, add_tx)),
+            Err(e) => {
+                info!("no discovery on {} {}: {}", name, network, e);
+                None
+            },
+        }
+    }
+
+    /// the probe senders of the interfaces running discovery
+    fn disc_txs(&self) -> Vec<tokio::sync::mpsc::Sender<String>> {
+        self.services.values().filter_map(|s| s.add_tx.clone()).collect()
+    }
+
+    /// receive the connections `accepter` takes until the returned task is aborted
+    pub fn start_service(&self, accepter: accepter::Accepter) -> tokio::task::JoinHandle<()> {
+        let key = self.public_key.clone();
+        let receiver = self.receiver.clone();
+        tokio::spawn(async move{
+            loop {
+                let (mut stream, addr) = match accepter.incoming().await {
+                    Ok(incoming) => incoming,
+                    Err(e) => {
+                        // e.g. out of file descriptors, don't spin on it
+                        info!("accept failed: {}", e);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    },
+                };
+                let key = key.clone();
+                let receiver = receiver.clone();
+                // a failing connection only ends its own task
+                tokio::spawn(async move {
+                    let result = match accepter::Accepter::handshake(&mut stream, &key).await {
+                        Ok(handshake) => {
+                            info!("accept addr {} key {}",addr,identity::fingerprint(&handshake.public_key));
+                            receiver.receive(stream, &addr.to_string(), handshake).await
+                        },
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = result {
+                        receiver.report(&addr.to_string(), &e);
+                    }
+                });
+            }
+        })
     }
 
     pub async fn sync_device_loop(&mut self,mut rx: tokio::sync::mpsc::Receiver<device::RemoteTcpDevice>) -> io::Result<()> {
         debug!("sync device loop");
-        if let Some(rx1) = self.rx.as_mut() {
+        if let Some(mut rx1) = self.rx.take() {
 
+            let mut interface_timer = tokio::time::interval(INTERFACE_POLL_INTERVAL);
+            let mut static_timer = tokio::time::interval(Duration::from_secs(self.config.discovery.static_probe_secs));
             loop {
                 debug!("wait for recv...");
                 tokio::select! {
                     device = rx.recv() => {
                         match device {
+                            Some(d) if d.left => self.device_left(d),
                             Some(d) => {
                                 debug!("receive device {:#?}",d);
-                                let mut devices = self.devices.lock().unwrap();
-                                devices.push(d);
-                                self.ui_ctx.request_repaint();
+                                let found = {
+                                    let mut devices = self.devices.lock().unwrap();
+                                    // one entry per device, its addresses on all interfaces merged
+                                    match devices.iter_mut().find(|old| old.device.id == d.device.id) {
+                                        Some(old) => {
+                                            old.merge(d);
+                                            if !old.relayed {
+                                                self.known_peers.update(old);
+                                            }
+                                            None
+                                        },
+                                        None => {
+                                            if devices.len() >= self.config.discovery.max_devices {
+                                                debug!("drop {}: already tracking {} devices", d.device.id, devices.len());
+                                                continue;
+                                            }
+                                            if !d.relayed {
+                                                self.known_peers.update(&d);
+                                            }
+                                            let info = DeviceInfo::from(&d);
+                                            devices.push(d);
+                                            Some(info)
+                                        },
+                                    }
+                                };
+                                match found {
+                                    Some(device) => self.subscribers.publish(Event::DeviceFound { device }),
+                                    None => self.subscribers.changed(),
+                                }
                             },
                             None => break,
                         }
                     }
-                    recv_ip = rx1.recv() => {
-                        match recv_ip {
-                            Some(ip) => {
+                    command = rx1.recv() => {
+                        match command {
+                            Some(Command::AddPeer(ip)) => {
                                 debug!("add ip {}",ip);
-                                for tx in &self.disc_txs {
-                                    tx.send(ip.clone()).await.expect("send failed");
+                                if ShareUri::is_share_uri(&ip) {
+                                    self.add_share_uri(&ip).await;
+                                } else {
+                                    self.start_sweep(ip);
+                                }
+                            },
+                            Some(Command::SendFiles { device, files, reply }) => {
+                                let result = self.start_send(&device, files);
+                                match reply {
+                                    Some(reply) => {
+                                        let _ = reply.send(result);
+                                    },
+                                    None => if let Err(e) = result {
+                                        self.subscribers.publish(Event::error(format!("send to {} failed: {}", device, e)));
+                                    },
+                                }
+                            },
+                            Some(Command::Cancel(id)) => {
+                                if let Some(transfer) = self.transfers.cancel(id) {
+                                    info!("transfer {} cancelled", id);
+                                    self.subscribers.publish(Event::TransferFinished { transfer });
+                                }
+                            },
+                            Some(Command::Accept(id)) => self.answer_offer(id, true),
+                            Some(Command::Decline(id)) => self.answer_offer(id, false),
+                            Some(Command::SetSettings(settings)) => {
+                                self.announcer.apply(&settings);
+                                if let Err(e) = settings.save() {
+                                    info!("save settings failed: {}", e);
+                                    self.subscribers.publish(Event::error(format!("save settings failed: {}", e)));
                                 }
                             },
-                            None => {
+                            Some(Command::Shutdown) | None => {
                                 break;
                             }
                         }
                     }
+                    _ = static_timer.tick(), if !self.config.peers.is_empty() => {
+                        self.probe_static_peers();
+                    }
+                    _ = interface_timer.tick() => {
+                        self.refresh_interfaces().await;
+                        if let Err(e) = self.known_peers.save() {
+                            info!("save known peers failed: {}", e);
+                        }
+                    }
                 };
             }
         }
+        if let Err(e) = self.known_peers.save() {
+            info!("save known peers failed: {}", e);
+        }
         
         Ok(())
     }
 
-    pub async fn send_files(&self, addr: SocketAddr,files: &Vec<std::path::PathBuf>) -> io::Result<()> {
-        debug!("send file {:?} to {}", files,addr);
-        let mut conn = ClientConnector::connect(addr).await?;
-        conn.send_public_key(&self.public_key).await?;
-        conn.send_files(files).await?;
-        Ok(())
+    /// a device said goodbye. only the key it announced itself with may remove it, pinned
+    /// devices stay listed as unreachable.
+    fn device_left(&self, d: RemoteTcpDevice) {
+        let mut lost = None;
+        let mut changed = false;
+        self.devices.lock().unwrap().retain_mut(|old| {
+            if old.device.id != d.device.id || old.fingerprint.is_none() || old.fingerprint != d.fingerprint {
+                return true;
+            }
+            info!("{} went offline", old.device.name);
+            if old.pinned {
+                old.reachable = false;
+                changed = true;
+                return true;
+            }
+            lost = Some((old.device.id.clone(), old.device.name.clone()));
+            false
+        });
+        match lost {
+            Some((id, name)) => self.subscribers.publish(Event::DeviceLost { id, name }),
+            None if changed => self.subscribers.changed(),
+            None => {},
+        }
+    }
+
+    /// accept or decline an incoming transfer waiting for the user
+    fn answer_offer(&self, id: u64, accept: bool) {
+        if !self.transfers.answer(id, accept) {
+            self.subscribers.publish(Event::error(format!("transfer {} is not waiting for an answer", id)));
+        }
     }
 
-    /// get remote device tcp socket address
-    fn get_device_addr(&self,id: &str) -> Option<SocketAddr> {
-        let devices = self.devices.lock().unwrap();
-        for disc in devices.iter() {
-            if &disc.device.id == id {
-                return Some(disc.addr.clone())
+    /// probe the addresses, ranges or host names typed in the add box,
+    /// the result is reported to the ui
+    fn start_sweep(&self, input: String) {
+        let disc_txs = self.disc_txs();
+        let devices = self.devices.clone();
+        let subscribers = self.subscribers.clone();
+        tokio::spawn(async move {
+            let notify = |msg: String| subscribers.publish(Event::status(msg));
+            let targets = match sweeper::parse_targets(&input).await {
+                Ok(targets) => targets,
+                Err(e) => {
+                    subscribers.publish(Event::error(format!("{}: {}", input, e)));
+                    return;
+                }
+            };
+            notify(format!("probing {} addresses...", targets.len()));
+            match sweeper::sweep(&targets, &disc_txs, &devices).await {
+                Ok((probed, answered)) => notify(format!("{}: {} addresses probed, {} devices answered", input, probed, answered)),
+                Err(e) => subscribers.publish(Event::error(format!("{}: {}", input, e))),
+            }
+        });
+    }
+
+    /// probe the peers from the configuration in the background
+    fn probe_static_peers(&self) {
+        let peers = self.config.peers.clone();
+        let disc_txs = self.disc_txs();
+        let devices = self.devices.clone();
+        let stale = Duration::from_secs(self.config.discovery.static_probe_secs * 2);
+        let port = self.config.receive.peer_port();
+        let subscribers = self.subscribers.clone();
+        tokio::spawn(async move {
+            if static_peers::probe(&peers, port, stale, &disc_txs, &devices).await {
+                subscribers.changed();
             }
+        });
+    }
+
+    /// add and pin the device of a drop:// uri without waiting for discovery
+    async fn add_share_uri(&mut self, text: &str) {
+        let msg = match ShareUri::parse(text, self.config.receive.peer_port()) {
+            Ok(uri) if uri.addrs.is_empty() => format!("{} has no address", uri.device.name),
+            Ok(uri) => {
+                if let Some(fingerprint) = &uri.fingerprint {
+                    self.pins.lock().unwrap().insert(uri.device.id.clone(), fingerprint.clone());
+                }
+                {
+                    // not heard from yet
+                    let mut device = RemoteTcpDevice::unreachable(&uri.addrs, uri.device.clone());
+                    device.pinned = true;
+                    device.fingerprint = uri.fingerprint.clone();
+                    for addr in uri.addrs.iter().rev() {
+                        device.addr = *addr;
+                        self.known_peers.update(&device);
+                    }
+                    device.addr = uri.addrs[0];
+                    let mut devices = self.devices.lock().unwrap();
+                    devices.retain(|old| old.device.id != uri.device.id);
+                    devices.push(device);
+                }
+                // ask the device to announce itself so its details get refreshed
+                let disc_txs = self.disc_txs();
+                for addr in &uri.addrs {
+                    for tx in &disc_txs {
+                        let _ = tx.send(addr.ip().to_string()).await;
+                    }
+                }
+                format!("added {}", uri.device.name)
+            },
+            Err(e) => {
+                info!("{}: {}", text, e);
+                self.subscribers.publish(Event::error(format!("{}: {}", text, e)));
+                return;
+            },
+        };
+        info!("{}", msg);
+        self.subscribers.publish(Event::status(msg));
+    }
+
+    /// the drop:// uri other devices can use to add this one
+    pub fn share_uri(&self) -> String {
+        // the ports the accepters got, they differ per interface with port 0
+        let addrs = self.services.iter()
+            .map(|(network, service)| SocketAddr::new(network.ip().into(), service.port))
+            .collect();
+        ShareUri {
+            device: self.host.clone(),
+            addrs,
+            fingerprint: Some(identity::fingerprint(&self.public_key)),
+        }.to_string()
+    }
+
+    /// sends from this device over the routes of the current interfaces
+    pub fn sender(&self) -> sender::Sender {
+        sender::Sender::new(self.host.id.clone(), self.public_key.clone(), self.services.keys().cloned().collect(), self.devices.clone(), self.config.transfer.buffer_size)
+    }
+
+    /// send `files` to `device`, directly over its best address or through the relay
+    pub async fn send_files(&self, device: &RemoteTcpDevice,files: &[std::path::PathBuf]) -> error::Result<()> {
+        self.sender().send(device, files, |_, _| {}).await
+    }
+
+    /// send `files` in the background to the device with id, address or name `target`,
+    /// returns the transfer id. progress is kept in `transfers`, the end is published.
+    fn start_send(&self, target: &str, files: Vec<std::path::PathBuf>) -> io::Result<u64> {
+        if let Some(file) = files.iter().find(|f| !f.is_file()) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} is no file", file.display())));
         }
-        return None;
+        let device = device::pick(&self.devices.lock().unwrap(), target)?.clone();
+        let transfer = self.transfers.add(&device, files.clone());
+        let id = transfer.id;
+        info!("transfer {}: send {} files to {}", id, files.len(), device.device.name);
+        self.subscribers.publish(Event::TransferStarted { transfer });
+
+        let sender = self.sender();
+        let transfers = self.transfers.clone();
+        let subscribers = self.subscribers.clone();
+        let task = tokio::spawn(async move {
+            let result = sender.send(&device, &files, transfers.reporter(id, subscribers.clone())).await;
+            let state = match result {
+                Ok(()) => TransferState::Done,
+                Err(error::Error::Rejected) => TransferState::Declined,
+                Err(error::Error::Cancelled) => TransferState::Cancelled,
+                Err(e) => TransferState::Failed(e.to_string()),
+            };
+            info!("transfer {}: {:?}", id, state);
+            if let Some(transfer) = transfers.finish(id, state) {
+                subscribers.publish(Event::TransferFinished { transfer });
+            }
+        });
+        self.transfers.set_task(id, task.abort_handle());
+        Ok(id)
     }
 }
 
@@ -3,86 +3,153 @@ use tokio::io::{self, AsyncRead, AsyncWrite, AsyncReadExt,AsyncWriteExt};
 use rsa::RsaPublicKey;
 use rsa::pkcs8::{EncodePublicKey,DecodePublicKey};
 use log::debug;
-use crate::key_object::KeyObject;
+use crate::config::DEFAULT_BUFFER_SIZE;
+use crate::error::{Error, Result};
+use crate::key_object::{KeyObject, ENCRYPTION_CHACHA20POLY1305};
 use crate::file_meta::{MetaList,file_md5};
 use std::io::Write;
+use std::path::{Path, PathBuf};
 
+/// tcp port receivers listen on
 pub const TCP_ACCEPTER_PORT: u16 = 52638u16;
+/// largest key object accepted in the handshake
+pub(crate) const MAX_KEY_OBJECT: usize = 16 * 1024;
+/// largest file list accepted
+const MAX_META_LIST: usize = 1024 * 1024;
 
+/// what the sender sent in the key exchange
+pub struct Handshake {
+    pub public_key: RsaPublicKey,
+    /// a session key follows, see `secure::accept`
+    pub encrypted: bool,
+}
+
+/// receives files sent to one address
 #[derive(Debug)]
 pub struct Accepter {
     tcp_listener: TcpListener,
 }
 
 impl Accepter{
-    pub async fn new(ip:&str) -> io::Result<Self> {
-        let tcp_listener = TcpListener::bind(format!("{}:{}",ip,TCP_ACCEPTER_PORT)).await?;
+    /// listen on `ip` and `port`, usually [`TCP_ACCEPTER_PORT`]. with port 0 the system
+    /// picks a free one, see [`Accepter::port`]
+    pub async fn new(ip:&str, port: u16) -> io::Result<Self> {
+        let tcp_listener = TcpListener::bind(format!("{}:{}",ip,port)).await?;
 
         Ok(Self{tcp_listener})
     }
 
-    pub async fn accept(&self,self_key:& RsaPublicKey) -> io::Result<(TcpStream,std::net::SocketAddr)> {
-        let (mut stream, addr) = self.tcp_listener.accept().await?;
+    /// the port actually listened on, to announce it
+    pub fn port(&self) -> io::Result<u16> {
+        Ok(self.tcp_listener.local_addr()?.port())
+    }
 
-        let mut buf = Vec::<u8>::new();
-        let public_key = loop {
-            let mut data = Vec::<u8>::new();
-            let lens = stream.read_buf(&mut data).await?;
-            if lens == 0 {
-                return Err(io::Error::new(io::ErrorKind::ConnectionAborted,"connect is closed"));
-            }
-            buf.append(&mut data);
-            if let Ok(keyobject) = serde_json::from_slice::<KeyObject>(&buf){
-                buf.clear();
-                if keyobject.r#type != "rsa" {
-                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "public key type must rsa"));
-                }
+    /// wait for the next sender and exchange public keys with it
+    pub async fn accept(&self,self_key:& RsaPublicKey) -> Result<(TcpStream,std::net::SocketAddr,Handshake)> {
+        let (mut stream, addr) = self.incoming().await?;
+        let handshake = Self::handshake(&mut stream, self_key).await?;
+        Ok((stream,addr,handshake))
+    }
 
-                let key_data = RsaPublicKey::from_public_key_pem(&keyobject.data).expect("public key is invalid");
-                break key_data;
-            }
+    /// wait for the next connection, the handshake is up to the caller so a slow
+    /// sender doesn't hold up the others
+    pub async fn incoming(&self) -> Result<(TcpStream,std::net::SocketAddr)> {
+        Ok(self.tcp_listener.accept().await?)
+    }
+
+    /// exchange public keys with the sender
+    pub async fn handshake<T: AsyncWrite + AsyncRead + Unpin + Send>(stream: &mut T, self_key: &RsaPublicKey) -> Result<Handshake> {
+        let keyobject: KeyObject = read_json(stream, MAX_KEY_OBJECT).await?;
+        if keyobject.r#type != "rsa" {
+            return Err(Error::protocol("public key type must rsa"));
+        }
+        let encrypted = match keyobject.encryption.as_deref() {
+            None => false,
+            Some(ENCRYPTION_CHACHA20POLY1305) => true,
+            Some(other) => return Err(Error::protocol(format!("unsupported encryption {}", other))),
         };
+        let public_key = RsaPublicKey::from_public_key_pem(&keyobject.data)
+            .map_err(|e| Error::crypto(format!("invalid public key: {}", e)))?;
 
         debug!("send public key");
-        self.send_public_key(self_key,&mut stream).await?;
+        Self::send_public_key(self_key,stream).await?;
 
-        Ok((stream,addr))
+        Ok(Handshake { public_key, encrypted })
     }
 
-    async fn send_public_key<T: AsyncWrite + Unpin + Send>(&self, self_key: &RsaPublicKey, tx: &mut T) -> io::Result<()> {
+    async fn send_public_key<T: AsyncWrite + Unpin + Send>(self_key: &RsaPublicKey, tx: &mut T) -> Result<()> {
         let key_response = KeyObject {
             r#type: "rsa".to_string(),
-            data: self_key.to_public_key_pem(base64ct::LineEnding::LF).expect("can't covert to pem"),
+            data: self_key.to_public_key_pem(base64ct::LineEnding::LF).map_err(Error::crypto)?,
+            encryption: None,
         };
-        let response_data = serde_json::to_vec(&key_response).expect("cannot find key");
+        let response_data = serde_json::to_vec(&key_response)?;
         tx.write_all(&response_data).await?;
         Ok(())
     }
 
-    pub async fn recv_files<T: AsyncWrite + AsyncRead + Unpin + Send>(stream: &mut T) -> io::Result<()> {
+    /// receive the file list, ask `accept` whether to take it and receive the contents
+    /// into `dir`. returns the list, `None` when it was declined.
+    pub async fn recv_files<T, F>(stream: &mut T, dir: &Path, accept: F) -> Result<Option<MetaList>>
+    where
+        T: AsyncWrite + AsyncRead + Unpin + Send,
+        F: FnOnce(&MetaList) -> bool,
+    {
+        let meta_list = Self::recv_meta(stream).await?;
+        if !accept(&meta_list) {
+            Self::decline(stream).await?;
+            return Ok(None);
+        }
+        Self::recv_accepted(stream, dir, &meta_list, DEFAULT_BUFFER_SIZE, |_, _| {}).await?;
+        Ok(Some(meta_list))
+    }
+
+    /// receive the file list the sender offers
+    pub async fn recv_meta<T: AsyncRead + Unpin + Send>(stream: &mut T) -> Result<MetaList> {
         debug!("wait recv files meta");
-        let mut buf = Vec::<u8>::new();
-        //receive meta
-        let meta_list = loop {
-            let mut data = Vec::<u8>::new();
-            let lens = stream.read_buf(&mut data).await?;
-            if lens == 0 {
-                return Err(io::Error::new(io::ErrorKind::ConnectionAborted,"connect is close"));
-            }
-            buf.append(&mut data);
-            if let Ok(meta_list) = serde_json::from_slice::<MetaList>(&buf) {
-                buf.clear();
-                break meta_list;
-            }
-        };
+        let meta_list: MetaList = read_json(stream, MAX_META_LIST).await?;
+        // the sizes are the sender's, a wrapped total would pass any size limit
+        if meta_list.total_size().is_none() {
+            return Err(Error::protocol("file sizes add up to more than 2^64 bytes"));
+        }
         debug!("recv files meta success");
+        Ok(meta_list)
+    }
+
+    /// tell the sender the files are not wanted
+    pub async fn decline<T: AsyncWrite + Unpin + Send>(stream: &mut T) -> Result<()> {
+        stream.write_u8(0x00).await?;
+        stream.flush().await?;
+        Ok(())
+    }
+
+    /// take the offered files and receive them into `dir`, `progress` gets the bytes
+    /// received so far and the total
+    pub async fn recv_accepted<T, F>(stream: &mut T, dir: &Path, meta_list: &MetaList, buffer_size: usize, mut progress: F) -> Result<()>
+    where
+        T: AsyncWrite + AsyncRead + Unpin + Send,
+        F: FnMut(u64, u64),
+    {
+        let total = meta_list.total_size()
+            .ok_or_else(|| Error::protocol("file sizes add up to more than 2^64 bytes"))?;
+        let mut received = 0;
+        // names are the sender's, never let them leave the download directory
+        let mut targets = Vec::new();
+        for meta in &meta_list.files {
+            match Path::new(&meta.name).file_name() {
+                Some(name) if name == meta.name.as_str() => targets.push(dir.join(name)),
+                _ => return Err(Error::protocol(format!("invalid file name {:?}", meta.name))),
+            }
+        }
+        std::fs::create_dir_all(dir)?;
 
         // wait for ack
         stream.write_u8(0x01).await?;
         
-        for meta in meta_list.files {
+        for (meta, target) in meta_list.files.iter().zip(targets) {
             // recv files
-            let temp_name = meta.name.clone() + ".droptmp";
+            let mut temp_name = target.clone().into_os_string();
+            temp_name.push(".droptmp");
             let temp_name_path = std::path::PathBuf::from(&temp_name);
             if temp_name_path.exists() {
                 if temp_name_path.is_dir() {
@@ -91,44 +158,146 @@ impl Accepter{
                     std::fs::remove_file(&temp_name_path)?;
                 }
             }
-            let mut file = std::fs::File::create(&temp_name).expect("create failed");
+            let mut file = std::fs::File::create(&temp_name)?;
+            let temp = TempFile { path: temp_name_path, done: false };
             let mut need_size = meta.size;
+            let mut data = vec![0; buffer_size];
             while need_size > 0 {
-                let lens = if need_size >= 10240 {
-                    let mut data = [0;10240];
-                    let lens = stream.read_exact(&mut data).await.expect("read failed");
-                    if lens == 0{
-                        break;
-                    }
-                    file.write_all(&data).expect("write failed");
-                    lens
-                } else {
-                    let mut data = Vec::<u8>::new();
-                    let lens = stream.read_to_end(&mut data).await.expect("read failed");
-                    if lens == 0 {
-                        break;
-                    }
-                    file.write_all(&data).expect("write failed");
-                    lens
-                };
-                
-                need_size = need_size - lens as u64;
-                debug!("Percent: {}%",(meta.size - need_size) * 100 / meta.size)
+                // only this file's bytes, the next file follows right after
+                let lens = need_size.min(data.len() as u64) as usize;
+                stream.read_exact(&mut data[..lens]).await?;
+                file.write_all(&data[..lens])?;
+
+                need_size -= lens as u64;
+                received += lens as u64;
+                progress(received, total);
+                debug!("Percent: {}%",(meta.size - need_size) as u128 * 100 / meta.size as u128)
             }
-            file.flush().expect("write failed");
+            file.flush()?;
 
             // file check
-            let path = std::path::PathBuf::from(&temp_name);
-            let md5 = file_md5(&path)?;
+            let md5 = file_md5(&temp.path)?;
             if md5 == meta.verity.data {
-                std::fs::rename(&temp_name, &meta.name).expect("rename failed");
+                temp.persist(&target)?;
                 debug!("recv {} success", meta.name);
             } else {
-                return Err(io::Error::new(io::ErrorKind::InvalidData, "file check failed"));
+                return Err(Error::protocol(format!("{} doesn't match its checksum", meta.name)));
             }
         }
+        // the sender ends its side after the last file, an encrypted session with the end
+        // frame. closing first would fail that.
+        if stream.read(&mut [0u8; 1]).await? != 0 {
+            return Err(Error::protocol("data after the last file"));
+        }
         Ok(())
     }
 
 }
 
+/// A file being received. It is removed when dropped before `persist`, so a failed,
+/// cancelled or aborted transfer leaves no `.droptmp` file behind.
+struct TempFile {
+    path: PathBuf,
+    done: bool,
+}
+
+impl TempFile {
+    /// move the complete file to `target`
+    fn persist(mut self, target: &Path) -> io::Result<()> {
+        std::fs::rename(&self.path, target)?;
+        self.done = true;
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.done {
+            debug!("remove incomplete {}", self.path.display());
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// read one json value, the peer sends no delimiter so it is parsed as it grows.
+/// more than `limit` bytes without a complete value is an error.
+pub(crate) async fn read_json<T, R>(stream: &mut R, limit: usize) -> Result<T>
+where
+    T: serde::de::DeserializeOwned,
+    R: AsyncRead + Unpin + Send,
+{
+    let mut buf = Vec::<u8>::new();
+    loop {
+        let lens = stream.read_buf(&mut buf).await?;
+        if lens == 0 {
+            return Err(Error::Io(io::Error::new(io::ErrorKind::ConnectionAborted,"connect is closed")));
+        }
+        if let Ok(value) = serde_json::from_slice::<T>(&buf) {
+            return Ok(value);
+        }
+        if buf.len() > limit {
+            return Err(Error::protocol(format!("no complete message in {} bytes", buf.len())));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn recv_meta_rejects_overflowing_sizes() {
+        let (mut sender, mut receiver) = tokio::io::duplex(4096);
+        let list = format!(
+            r#"{{"files":[{{"name":"a","size":{},"verity":{{"type":"md5","data":""}}}},{{"name":"b","size":2,"verity":{{"type":"md5","data":""}}}}]}}"#,
+            u64::MAX,
+        );
+        sender.write_all(list.as_bytes()).await.unwrap();
+        assert!(matches!(Accepter::recv_meta(&mut receiver).await, Err(Error::Protocol(_))));
+    }
+
+    #[tokio::test]
+    async fn recv_files_never_asks_about_overflowing_sizes() {
+        let (mut sender, mut receiver) = tokio::io::duplex(4096);
+        let list = format!(
+            r#"{{"files":[{{"name":"a","size":{},"verity":{{"type":"md5","data":""}}}},{{"name":"b","size":{},"verity":{{"type":"md5","data":""}}}}]}}"#,
+            u64::MAX / 2 + 1, u64::MAX / 2 + 1,
+        );
+        sender.write_all(list.as_bytes()).await.unwrap();
+        let dir = std::env::temp_dir();
+        let result = Accepter::recv_files(&mut receiver, &dir, |_| panic!("asked about an invalid file list")).await;
+        assert!(matches!(result, Err(Error::Protocol(_))));
+    }
+
+    /// what `recv_accepted` makes of `data` sent for the 2 byte file "a" and the end of the stream
+    async fn receive(data: &'static [u8]) -> Result<()> {
+        let (mut sender, mut receiver) = tokio::io::duplex(4096);
+        let dir = std::env::temp_dir().join(format!("rsdrop-recv-test-{}-{}", std::process::id(), data.len()));
+        let meta_list = MetaList { files: vec![crate::file_meta::FileMeta {
+            name: "a".to_string(),
+            size: 2,
+            verity: crate::file_meta::FileVerity { r#type: "md5".to_string(), data: "49f68a5c8493ec2c0bf489821c21fc3b".to_string() },
+        }] };
+        let send = async move {
+            assert_eq!(sender.read_u8().await.unwrap(), 0x01);
+            sender.write_all(data).await.unwrap();
+            sender.shutdown().await.unwrap();
+        };
+        let (result, _) = tokio::join!(Accepter::recv_accepted(&mut receiver, &dir, &meta_list, 1024, |_, _| {}), send);
+        let _ = std::fs::remove_dir_all(&dir);
+        result
+    }
+
+    #[tokio::test]
+    async fn recv_accepted_waits_for_the_end() {
+        assert!(receive(b"hi").await.is_ok());
+        assert!(matches!(receive(b"hi!").await, Err(Error::Protocol(_))));
+    }
+
+    #[tokio::test]
+    async fn read_json_limit() {
+        let (mut sender, mut receiver) = tokio::io::duplex(64 * 1024);
+        sender.write_all(&[b'['; 2048]).await.unwrap();
+        assert!(matches!(read_json::<Vec<u8>, _>(&mut receiver, 1024).await, Err(Error::Protocol(_))));
+    }
+}
@@ -0,0 +1,92 @@
+use serde::Serialize;
+use std::fmt;
+use std::path::PathBuf;
+use tokio::io;
+use tokio::sync::oneshot;
+
+use crate::device::DeviceInfo;
+use crate::settings::Settings;
+use super::transfers::{TransferInfo, TransferState};
+
+/// What front ends ask the controller to do, sent over the channel from
+/// [`Controller::gen_ctx`](super::Controller::gen_ctx).
+#[derive(Debug)]
+pub enum Command {
+    /// probe ip addresses, ranges or host names, or add the device of a drop:// uri
+    AddPeer(String),
+    /// send files to the device with this id, name or address
+    SendFiles {
+        device: String,
+        files: Vec<PathBuf>,
+        /// gets the id of the started transfer
+        reply: Option<oneshot::Sender<io::Result<u64>>>,
+    },
+    /// stop a transfer, an offered one is declined
+    Cancel(u64),
+    /// take the files of an offered transfer
+    Accept(u64),
+    Decline(u64),
+    /// change the visibility and rooms, saved right away
+    SetSettings(Settings),
+    /// say goodbye on every interface and stop, running transfers get a few seconds to
+    /// finish, see `Controller::start_loop`
+    Shutdown,
+}
+
+/// What the controller tells front ends.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// a message for the user, e.g. the result of a probe
+    Status { message: String },
+    /// something went wrong outside of a transfer, e.g. a connection that failed early
+    Error { message: String },
+    /// a device not in the list before
+    DeviceFound { device: DeviceInfo },
+    /// a device removed from the list, e.g. when the network it was in went away
+    DeviceLost { id: String, name: String },
+    /// a sender waits for `Command::Accept` or `Command::Decline`
+    IncomingOffer { transfer: TransferInfo },
+    TransferStarted { transfer: TransferInfo },
+    /// sent whenever another percent is done
+    TransferProgress { id: u64, sent: u64, total: u64 },
+    /// done, failed, cancelled or declined, see `transfer.state`
+    TransferFinished { transfer: TransferInfo },
+}
+
+impl Event {
+    pub fn status(message: String) -> Self {
+        Event::Status { message }
+    }
+
+    pub fn error(message: String) -> Self {
+        Event::Error { message }
+    }
+}
+
+/// a line for the status bar or the log
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Status { message } => write!(f, "{}", message),
+            Event::Error { message } => write!(f, "error: {}", message),
+            Event::DeviceFound { device } => write!(f, "found {} on {}", device.name, device.addrs.join(", ")),
+            Event::DeviceLost { name, .. } => write!(f, "lost {}", name),
+            Event::IncomingOffer { transfer } => write!(f, "{} wants to send {} files, {} bytes", transfer.name, transfer.files.len(), transfer.total),
+            Event::TransferStarted { transfer } if transfer.incoming => write!(f, "receiving {} files from {}", transfer.files.len(), transfer.name),
+            Event::TransferStarted { transfer } => write!(f, "sending {} files to {}", transfer.files.len(), transfer.name),
+            Event::TransferProgress { id, sent, total } => write!(f, "transfer {}: {}%", id, (sent * 100).checked_div(*total).unwrap_or(100)),
+            Event::TransferFinished { transfer } => {
+                let direction = if transfer.incoming { "receiving from" } else { "sending to" };
+                match &transfer.state {
+                    TransferState::Failed(e) => write!(f, "{} {} failed: {}", direction, transfer.name, e),
+                    TransferState::Cancelled => write!(f, "{} {} cancelled", direction, transfer.name),
+                    TransferState::Declined if transfer.incoming => write!(f, "declined {} files from {}", transfer.files.len(), transfer.name),
+                    TransferState::Declined => write!(f, "{} declined {} files", transfer.name, transfer.files.len()),
+                    _ if transfer.incoming => write!(f, "received {} files from {}", transfer.files.len(), transfer.name),
+                    _ => write!(f, "sent {} files to {}", transfer.files.len(), transfer.name),
+                }
+            },
+        }
+    }
+}
@@ -1,57 +1,81 @@
 use tokio::net::TcpStream;
-use tokio::io::{self, AsyncReadExt,AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncReadExt,AsyncWriteExt};
 
 use log::debug;
 
+use super::accepter::{read_json, MAX_KEY_OBJECT};
 use rsa::RsaPublicKey;
 use rsa::pkcs8::{EncodePublicKey,DecodePublicKey};
-use crate::key_object::KeyObject;
+use crate::config::DEFAULT_BUFFER_SIZE;
+use crate::error::{Error, Result};
+use crate::key_object::{KeyObject, ENCRYPTION_CHACHA20POLY1305};
 use crate::file_meta::{FileMeta,MetaList};
 use std::io::Read;
 
 //const TCP_CONNECTOR_PORT: u16 = 52638u16;
 
+/// a stream files can be sent over, plain tcp or an encrypted session
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+/// sends files to one receiver
 pub struct ClientConnector {
-    pub tcp_connector: TcpStream,
+    pub tcp_connector: Box<dyn Transport>,
+    /// bytes of a file read and sent at once
+    buffer_size: usize,
 }
 
 impl ClientConnector {
-    pub async fn connect<A: tokio::net::ToSocketAddrs>(addr: A) -> io::Result<Self> {
+    /// open a tcp connection to a receiver
+    pub async fn connect<A: tokio::net::ToSocketAddrs>(addr: A) -> Result<Self> {
         let tcp_connector = TcpStream::connect(addr).await?;
         tcp_connector.set_nodelay(true)?;
-        Ok(Self { tcp_connector, })
+        Ok(Self::from_stream(tcp_connector))
+    }
+
+    /// use an already connected stream, e.g. one opened through the relay
+    pub fn from_stream<T: Transport + 'static>(stream: T) -> Self {
+        Self { tcp_connector: Box::new(stream), buffer_size: DEFAULT_BUFFER_SIZE }
+    }
+
+    pub fn with_buffer_size(mut self, buffer_size: usize) -> Self {
+        self.buffer_size = buffer_size;
+        self
+    }
+
+    /// encrypt everything sent after the key exchange
+    pub async fn secure(self, peer_key: &RsaPublicKey) -> Result<Self> {
+        let buffer_size = self.buffer_size;
+        let stream = super::secure::offer(self.tcp_connector, peer_key).await?;
+        Ok(Self::from_stream(stream).with_buffer_size(buffer_size))
     }
 
-    pub async fn send_public_key(&mut self, key: &RsaPublicKey) -> io::Result<()> {
+    /// exchange public keys, returns the key of the receiver.
+    /// with `encrypt` the receiver waits for a session key, call `secure` next.
+    pub async fn send_public_key(&mut self, key: &RsaPublicKey, encrypt: bool) -> Result<RsaPublicKey> {
         let key_response = KeyObject {
             r#type: "rsa".to_string(),
-            data: key.to_public_key_pem(base64ct::LineEnding::LF).expect("can't covert to pem"),
+            data: key.to_public_key_pem(base64ct::LineEnding::LF).map_err(Error::crypto)?,
+            encryption: encrypt.then(|| ENCRYPTION_CHACHA20POLY1305.to_string()),
         };
-        let response_data = serde_json::to_vec(&key_response).expect("cannot find key");
+        let response_data = serde_json::to_vec(&key_response)?;
         self.tcp_connector.write_all(&response_data).await?;
 
-        let mut buf = Vec::<u8>::new();
-        let public_key = loop {
-            let mut data = Vec::<u8>::new();
-            let lens = self.tcp_connector.read_buf(&mut data).await?;
-            if lens == 0 {
-                return Err(io::Error::new(io::ErrorKind::ConnectionAborted,"connect is close"));
-            }
-            buf.append(&mut data);
-            if let Ok(keyobject) = serde_json::from_slice::<KeyObject>(&buf){
-                buf.clear();
-                if keyobject.r#type != "rsa" {
-                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "public key type must rsa"));
-                }
+        let keyobject: KeyObject = read_json(&mut self.tcp_connector, MAX_KEY_OBJECT).await?;
+        if keyobject.r#type != "rsa" {
+            return Err(Error::protocol("public key type must rsa"));
+        }
+        RsaPublicKey::from_public_key_pem(&keyobject.data)
+            .map_err(|e| Error::crypto(format!("invalid public key: {}", e)))
+    }
 
-                let key_data = RsaPublicKey::from_public_key_pem(&keyobject.data).expect("public key is invalid");
-                break key_data;
-            }
-        };
-        Ok(())
+    /// send the file list, wait for the receiver to accept and send the contents
+    pub async fn send_files(&mut self, files: &Vec<std::path::PathBuf>) -> Result<()> {
+        self.send_files_with_progress(files, |_, _| {}).await
     }
 
-    pub async fn send_files(&mut self, files: &Vec<std::path::PathBuf>) -> io::Result<()> {
+    /// like `send_files`, `progress` is called with the bytes sent so far and the total
+    pub async fn send_files_with_progress<F: FnMut(u64, u64)>(&mut self, files: &Vec<std::path::PathBuf>, mut progress: F) -> Result<()> {
         let mut file_meta_list = MetaList{
             files: Vec::new(),
         };
@@ -62,14 +86,19 @@ impl ClientConnector {
         let request = serde_json::to_string(&file_meta_list)?;
         self.tcp_connector.write_all(request.as_bytes()).await?;
         let resp = self.tcp_connector.read_u8().await?;
-        if resp != 1 {
-            return Err(io::Error::new(io::ErrorKind::InvalidData,"response is error"));
+        match resp {
+            1 => {},
+            0 => return Err(Error::Rejected),
+            other => return Err(Error::protocol(format!("unknown answer {} to the file list", other))),
         }
+        let total = file_meta_list.total_size().unwrap_or(u64::MAX);
+        let mut sent = 0u64;
+        progress(sent, total);
 
         for file in files {
             let mut sum = 0;
             let mut f = std::fs::File::open(file)?;
-            let mut buf = [0;10240];
+            let mut buf = vec![0; self.buffer_size];
             loop {
                 let lens = f.read(&mut buf)?;
                 if lens == 0{
@@ -78,8 +107,12 @@ impl ClientConnector {
                 sum = sum + lens;
 
                 self.tcp_connector.write_all(&buf[..lens]).await?;
+                sent += lens as u64;
+                progress(sent, total);
             }
         }
+        // for an encrypted session this sends the end frame, so a cut off stream is noticed
+        self.tcp_connector.shutdown().await?;
         debug!("file send succeed!");
         Ok(())
     }
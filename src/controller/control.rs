@@ -0,0 +1,346 @@
+use tokio::io::{self, AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use log::{debug, info};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::device::{DeviceInfo, RemoteTcpDevice};
+use crate::settings::Settings;
+use crate::utils;
+use super::command::{Command, Event};
+use super::subscriber::Subscribers;
+use super::transfers::{TransferState, Transfers};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// the controller couldn't do what was asked
+const FAILED: i64 = -32000;
+/// longest request line, a client sending more is dropped
+const MAX_LINE: usize = 1024 * 1024;
+/// pause after a failed accept, e.g. when out of file descriptors
+const ACCEPT_RETRY: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// `$XDG_RUNTIME_DIR/rsdrop.sock`, or `rsdrop.sock` in the data directory
+pub fn default_socket() -> Option<PathBuf> {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir).join("rsdrop.sock")),
+        _ => utils::data_dir().map(|dir| dir.join("rsdrop.sock")),
+    }
+}
+
+/// what the api reads and drives, cheap to clone into client tasks
+#[derive(Clone)]
+pub struct Api {
+    /// weak so the api doesn't keep the controller running once the front end is gone
+    pub commands: mpsc::WeakSender<Command>,
+    pub devices: Arc<Mutex<Vec<RemoteTcpDevice>>>,
+    pub transfers: Transfers,
+    pub subscribers: Subscribers,
+}
+
+/// The listening socket, removed again when dropped.
+pub struct Server {
+    path: PathBuf,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.task.abort();
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Listen on `path` for api clients. Fails when another instance already serves it,
+/// a socket left behind by a crashed instance is replaced, any other file is left alone.
+pub async fn serve(path: PathBuf, api: Api) -> io::Result<Server> {
+    if let Ok(meta) = std::fs::symlink_metadata(&path) {
+        if !meta.file_type().is_socket() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} exists and is no socket", path.display())));
+        }
+        if UnixStream::connect(&path).await.is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is served by another instance", path.display())));
+        }
+        std::fs::remove_file(&path)?;
+    }
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let listener = UnixListener::bind(&path)?;
+    // other users must not drive this instance
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+    info!("control api listen on {}", path.display());
+
+    let task = tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    info!("control api accept failed: {}", e);
+                    tokio::time::sleep(ACCEPT_RETRY).await;
+                    continue;
+                },
+            };
+            let api = api.clone();
+            tokio::spawn(async move {
+                if let Err(e) = client(stream, api).await {
+                    debug!("control client closed: {}", e);
+                }
+            });
+        }
+    });
+    Ok(Server { path, task })
+}
+
+#[derive(Deserialize)]
+struct Request {
+    jsonrpc: Option<String>,
+    /// notifications have none and get no response
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+/// error response of a request
+struct Failure {
+    code: i64,
+    message: String,
+}
+
+impl Failure {
+    fn new(code: i64, message: impl ToString) -> Self {
+        Self { code, message: message.to_string() }
+    }
+}
+
+/// one json-rpc 2.0 message per line in both directions, events of a subscribed
+/// client are sent as `event` notifications
+async fn client(stream: UnixStream, api: Api) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    // survives a select round that an event won half way through a line
+    let mut buf = Vec::new();
+    let mut events: Option<broadcast::Receiver<Event>> = None;
+    loop {
+        let next_event = async {
+            match events.as_mut() {
+                Some(events) => events.recv().await,
+                None => std::future::pending().await,
+            }
+        };
+        let message = tokio::select! {
+            line = next_line(&mut reader, &mut buf) => match line? {
+                Some(line) if line.trim().is_empty() => continue,
+                Some(line) => match handle(&line, &api, &mut events).await {
+                    Some(response) => response,
+                    None => continue,
+                },
+                None => return Ok(()),
+            },
+            event = next_event => match event {
+                Ok(event) => json!({ "jsonrpc": "2.0", "method": "event", "params": event }),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    debug!("control client missed {} events", missed);
+                    continue;
+                },
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+        };
+        let mut data = serde_json::to_vec(&message)?;
+        data.push(b'\n');
+        writer.write_all(&data).await?;
+    }
+}
+
+/// the next line without its line break, `None` at the end. what is read goes to `buf`
+/// first, so the future can be dropped and called again.
+async fn next_line<R: AsyncBufRead + Unpin>(reader: &mut R, buf: &mut Vec<u8>) -> io::Result<Option<String>> {
+    let limit = (MAX_LINE + 1).saturating_sub(buf.len()) as u64;
+    (&mut *reader).take(limit).read_until(b'\n', buf).await?;
+    // without a line break either the limit or the end of the stream stopped the read
+    if buf.last() != Some(&b'\n') {
+        if buf.len() > MAX_LINE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("request line longer than {} bytes", MAX_LINE)));
+        }
+        if buf.is_empty() {
+            return Ok(None);
+        }
+    }
+    let mut line = String::from_utf8(std::mem::take(buf))
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "request line is no utf-8"))?;
+    if line.ends_with('\n') {
+        line.pop();
+        if line.ends_with('\r') {
+            line.pop();
+        }
+    }
+    Ok(Some(line))
+}
+
+/// the response to one line, `None` for notifications
+async fn handle(line: &str, api: &Api, events: &mut Option<broadcast::Receiver<Event>>) -> Option<Value> {
+    let request: Request = match serde_json::from_str::<Value>(line) {
+        Ok(value) => match serde_json::from_value(value) {
+            Ok(request) => request,
+            Err(e) => return Some(response(Value::Null, Err(Failure::new(INVALID_REQUEST, e)))),
+        },
+        Err(e) => return Some(response(Value::Null, Err(Failure::new(PARSE_ERROR, e)))),
+    };
+    let result = if request.jsonrpc.as_deref() != Some("2.0") {
+        Err(Failure::new(INVALID_REQUEST, "jsonrpc must be \"2.0\""))
+    } else {
+        call(&request.method, request.params, api, events).await
+    };
+    request.id.map(|id| response(id, result))
+}
+
+fn response(id: Value, result: Result<Value, Failure>) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(e) => json!({ "jsonrpc": "2.0", "id": id, "error": { "code": e.code, "message": e.message } }),
+    }
+}
+
+#[derive(Deserialize)]
+struct AddParams {
+    address: String,
+}
+
+#[derive(Deserialize)]
+struct SendParams {
+    device: String,
+    files: Vec<PathBuf>,
+}
+
+#[derive(Deserialize)]
+struct TransferParams {
+    transfer: u64,
+}
+
+fn params<T: serde::de::DeserializeOwned>(params: Value) -> Result<T, Failure> {
+    serde_json::from_value(params).map_err(|e| Failure::new(INVALID_PARAMS, e))
+}
+
+async fn call(method: &str, params_value: Value, api: &Api, events: &mut Option<broadcast::Receiver<Event>>) -> Result<Value, Failure> {
+    match method {
+        "devices" => {
+            let devices: Vec<DeviceInfo> = api.devices.lock().unwrap().iter().map(DeviceInfo::from).collect();
+            Ok(json!(devices))
+        },
+        "add" => {
+            let p: AddParams = params(params_value)?;
+            command(api, Command::AddPeer(p.address)).await?;
+            Ok(Value::Null)
+        },
+        "send" => {
+            let p: SendParams = params(params_value)?;
+            if p.files.is_empty() {
+                return Err(Failure::new(INVALID_PARAMS, "no files given"));
+            }
+            // the app runs in another directory than the client
+            if let Some(file) = p.files.iter().find(|f| !f.is_absolute()) {
+                return Err(Failure::new(INVALID_PARAMS, format!("{} is no absolute path", file.display())));
+            }
+            let (tx, rx) = oneshot::channel();
+            command(api, Command::SendFiles { device: p.device, files: p.files, reply: Some(tx) }).await?;
+            match rx.await {
+                Ok(Ok(id)) => Ok(json!({ "transfer": id })),
+                Ok(Err(e)) => Err(Failure::new(FAILED, e)),
+                Err(_) => Err(Failure::new(FAILED, "the controller stopped")),
+            }
+        },
+        "transfers" => Ok(json!(api.transfers.list())),
+        "cancel" | "accept" | "decline" => {
+            let p: TransferParams = params(params_value)?;
+            match api.transfers.get(p.transfer) {
+                Some(transfer) if method != "cancel" && transfer.state != TransferState::Offered => {
+                    Err(Failure::new(FAILED, format!("transfer {} is not waiting for an answer", p.transfer)))
+                },
+                Some(transfer) if transfer.state.is_active() => {
+                    let c = match method {
+                        "accept" => Command::Accept(p.transfer),
+                        "decline" => Command::Decline(p.transfer),
+                        _ => Command::Cancel(p.transfer),
+                    };
+                    command(api, c).await?;
+                    Ok(Value::Null)
+                },
+                Some(_) => Err(Failure::new(FAILED, format!("transfer {} already ended", p.transfer))),
+                None => Err(Failure::new(FAILED, format!("no transfer {}", p.transfer))),
+            }
+        },
+        "set_settings" => {
+            let settings: Settings = params(params_value)?;
+            command(api, Command::SetSettings(settings)).await?;
+            Ok(Value::Null)
+        },
+        "subscribe" => {
+            if events.is_none() {
+                *events = Some(api.subscribers.events());
+            }
+            Ok(Value::Bool(true))
+        },
+        _ => Err(Failure::new(METHOD_NOT_FOUND, format!("no method {}", method))),
+    }
+}
+
+async fn command(api: &Api, command: Command) -> Result<(), Failure> {
+    let commands = api.commands.upgrade().ok_or_else(|| Failure::new(FAILED, "the controller stopped"))?;
+    commands.send(command).await.map_err(|_| Failure::new(FAILED, "the controller stopped"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn lines(data: &[u8]) -> io::Result<Vec<String>> {
+        let mut reader = BufReader::new(data);
+        let mut buf = Vec::new();
+        let mut lines = Vec::new();
+        while let Some(line) = next_line(&mut reader, &mut buf).await? {
+            lines.push(line);
+        }
+        Ok(lines)
+    }
+
+    #[tokio::test]
+    async fn next_line_splits() {
+        assert_eq!(lines(b"a\nb\r\n\nc").await.unwrap(), ["a", "b", "", "c"]);
+        assert!(lines(b"").await.unwrap().is_empty());
+        assert!(lines(b"\xff\n").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn next_line_limit() {
+        let mut data = vec![b'x'; MAX_LINE];
+        data.push(b'\n');
+        assert_eq!(lines(&data).await.unwrap()[0].len(), MAX_LINE);
+        let data = vec![b'x'; MAX_LINE + 1];
+        assert_eq!(lines(&data).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn serve_keeps_other_files() {
+        let path = std::env::temp_dir().join(format!("rsdrop-control-test-{}", std::process::id()));
+        std::fs::write(&path, "data").unwrap();
+        let (tx, _rx) = mpsc::channel(1);
+        let api = Api {
+            commands: tx.downgrade(),
+            devices: Arc::default(),
+            transfers: Transfers::default(),
+            subscribers: Subscribers::default(),
+        };
+        let result = serve(path.clone(), api).await;
+        let data = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(result.err().map(|e| e.kind()), Some(io::ErrorKind::AlreadyExists));
+        assert_eq!(data, "data");
+    }
+}
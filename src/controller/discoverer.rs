@@ -1,119 +1,434 @@
 use tokio::net::UdpSocket;
-use tokio::io;
 use serde::{Serialize, Deserialize};
-use std::sync::{Arc,Mutex};
-use tokio::io::{ AsyncReadExt, AsyncWriteExt, AsyncRead, AsyncWrite};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use log::{debug, info};
 use std::net::{SocketAddr, Ipv4Addr};
+use pnet::ipnetwork::Ipv4Network;
 
+use crate::config::{BroadcastMode, DiscoveryConfig};
 use crate::device::{Device,RemoteTcpDevice};
-use super::accepter;
+use crate::error::Result;
+use super::flood::{FloodGuard, MAX_DATAGRAM};
+use super::signing::{Announcer, Verifier};
 
-const VERSION: u32 = 1u32;
-const MULTICAST_IP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 123);
-const UDP_PORT:u16 = 52637u16;
+const VERSION: u32 = 2u32;
+/// oldest protocol version this one still talks to
+const MIN_VERSION: u32 = 1u32;
+/// default group of the discovery requests
+pub const MULTICAST_IP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 123);
+/// default udp port of the discovery requests
+pub const UDP_PORT:u16 = 52637u16;
 
+/// the transfer can be encrypted end to end
+pub const CAP_ENCRYPTION: &str = "encryption";
+/// features this version implements
+const CAPABILITIES: &[&str] = &[CAP_ENCRYPTION];
+
+/// the udp announcement of a device, answered with one having `ack` unset
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DiscoveryReq {
     pub version: u32,
+    /// oldest version the sender still talks to, missing before version 2
+    #[serde(default)]
+    pub min_version: u32,
     pub device: Device,
     pub port: u16,
     pub ack: bool,
+    /// the device goes offline, only signed ones are believed
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub bye: bool,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    /// base64 DER public key of the sender, signed requests only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+    /// unix time in milliseconds, with the nonce it keeps the request from being replayed
+    #[serde(default)]
+    pub timestamp: u64,
+    #[serde(default)]
+    pub nonce: String,
+    /// room tags, see `rooms::tag`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rooms: Vec<String>,
+    /// base64 rsa pkcs1v15 sha256 signature of `signed_payload`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
 }
 impl DiscoveryReq {
-    fn new(device: &Device, port: u16, ack: bool) -> Self {
+    pub fn new(device: &Device, port: u16, ack: bool) -> Self {
         DiscoveryReq {
             version: VERSION,
+            min_version: MIN_VERSION,
             device: device.clone(),
-            port: port,
+            port,
             ack,
+            bye: false,
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+            public_key: None,
+            timestamp: 0,
+            nonce: String::new(),
+            rooms: Vec::new(),
+            signature: None,
         }
     }
+
+    /// the fields covered by the signature, one per line
+    pub fn signed_payload(&self) -> Vec<u8> {
+        [
+            "rsdrop-discovery",
+            &self.version.to_string(),
+            &self.min_version.to_string(),
+            &self.device.id,
+            &self.device.name,
+            &self.device.r#type,
+            &self.port.to_string(),
+            // older versions don't know goodbyes, the signature fails for them
+            if self.bye { "bye" } else if self.ack { "ack" } else { "" },
+            &self.capabilities.join(","),
+            self.public_key.as_deref().unwrap_or(""),
+            &self.timestamp.to_string(),
+            &self.nonce,
+            &self.rooms.join(","),
+        ].join("\n").into_bytes()
+    }
+
+    /// both sides understand each other's protocol version
+    pub fn is_compatible(&self) -> bool {
+        self.version >= MIN_VERSION && self.min_version <= VERSION
+    }
+
+    /// the device behind this request, reachable at `addr`
+    pub fn into_remote(self, addr: SocketAddr) -> RemoteTcpDevice {
+        let compatible = self.is_compatible();
+        let mut remote = RemoteTcpDevice::new(addr, self.device);
+        remote.version = self.version;
+        remote.capabilities = self.capabilities;
+        remote.compatible = compatible;
+        remote.left = self.bye;
+        remote
+    }
+}
+
+/// where requests are sent and which accepter port they announce
+#[derive(Clone,Copy,Debug)]
+struct Ports {
+    discovery: u16,
+    accepter: u16,
 }
 
-/// discovery other devices by udp multicast 
+/// where a service sends its answers, to the port a request came from
+struct Reply {
+    socket: Arc<UdpSocket>,
+    ports: Ports,
+}
+
+/// discovery other devices by udp multicast, with a subnet broadcast fallback
 #[derive(Debug)]
 pub struct Discovery {
+    /// bound to the discovery port, shared with other instances on the address
     socket : Arc<UdpSocket>,
+    /// sends the requests and answers from a port of its own, so the answers to this
+    /// instance come back to it
+    send_socket: Arc<UdpSocket>,
+    /// bound to the subnet broadcast address to receive broadcast requests
+    broadcast_socket: Option<Arc<UdpSocket>>,
+    broadcast: Ipv4Addr,
+    config: DiscoveryConfig,
+    ports: Ports,
+    tasks: Vec<tokio::task::JoinHandle<()>>,
 }
 
 impl Discovery {
-    pub async fn new(ip: &str)  -> io::Result<Self> {
-        let socket = UdpSocket::bind(format!("{}:{}",ip ,UDP_PORT)).await?;
+    /// bind the discovery sockets on the address of `network`, the requests announce
+    /// the accepter on `accepter_port`
+    pub async fn new(network: &Ipv4Network, config: &DiscoveryConfig, accepter_port: u16)  -> Result<Self> {
+        let socket = bind_shared(SocketAddr::new(network.ip().into(), config.port)).map_err(|e| match e.kind() {
+            std::io::ErrorKind::AddrInUse => std::io::Error::new(e.kind(), format!("udp port {} is in use by another program, see discovery.port", config.port)),
+            _ => e,
+        })?;
         let inter = Ipv4Addr::new(0,0,0,0);
-        socket.join_multicast_v4(MULTICAST_IP,inter).expect("join failed");
-        socket.set_multicast_ttl_v4(50)?;
-        socket.set_multicast_loop_v4(false)?;
-        Ok(Self{socket: Arc::new(socket),})
+        socket.join_multicast_v4(config.multicast_ip,inter)?;
+        let send_socket = UdpSocket::bind(SocketAddr::new(network.ip().into(), 0)).await?;
+        send_socket.set_multicast_ttl_v4(config.multicast_ttl)?;
+        send_socket.set_multicast_loop_v4(false)?;
+
+        let broadcast = network.broadcast();
+        let broadcast_socket = if config.broadcast != BroadcastMode::Never && broadcast != network.ip() {
+            send_socket.set_broadcast(true)?;
+            match bind_shared(SocketAddr::new(broadcast.into(), config.port)) {
+                Ok(s) => Some(Arc::new(s)),
+                Err(e) => {
+                    info!("can't listen on broadcast address {}: {}", broadcast, e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        Ok(Self{
+            socket: Arc::new(socket),
+            send_socket: Arc::new(send_socket),
+            broadcast_socket,
+            broadcast,
+            config: config.clone(),
+            ports: Ports { discovery: config.port, accepter: accepter_port },
+            tasks: Vec::new(),
+        })
     }
 
-    pub async fn start(&self, dev: &Device,tx: tokio::sync::mpsc::Sender<crate::device::RemoteTcpDevice>) -> io::Result<tokio::sync::mpsc::Sender<String>> {
-        send_discovery(&self.socket,&MULTICAST_IP.to_string(), &dev).await?;
+    /// announce this device and report the devices found to `tx`. the returned sender
+    /// takes addresses to probe with a unicast request.
+    pub async fn start(&mut self, announcer: &Announcer, verifier: Arc<Verifier>, tx: tokio::sync::mpsc::Sender<crate::device::RemoteTcpDevice>) -> Result<tokio::sync::mpsc::Sender<String>> {
+        if announcer.visibility().announces() {
+            send_discovery(&self.send_socket,&self.config.multicast_ip.to_string(),self.ports,announcer).await?;
+        }
         
         let (add_tx,rx) = tokio::sync::mpsc::channel(20);
+        // set when a device answers on this interface, cleared by every broadcast fallback check
+        let responded = Arc::new(AtomicBool::new(false));
+        // shared by the multicast and broadcast services, a source gets one budget
+        let guard = Arc::new(FloodGuard::new(self.config.rate_limit));
         // recv
         // add
-        let recv_socket = self.socket.clone();
+        let recv_socket = self.send_socket.clone();
         let service_socket = self.socket.clone();
-        let recv_device = dev.clone();
-        let service_dev = dev.clone();
-        tokio::spawn(async move {
-            service(service_socket,&service_dev,tx).await.expect("abc");
-        });
-        tokio::spawn(async move {
-            receive_handle(recv_socket,&recv_device,rx).await.expect("abc");
-        });
+        let reply_socket = self.send_socket.clone();
+        let recv_announcer = announcer.clone();
+        let service_announcer = announcer.clone();
+        let service_verifier = verifier.clone();
+        let service_guard = guard.clone();
+        let service_tx = tx.clone();
+        let service_responded = responded.clone();
+        let ports = self.ports;
+        self.tasks.push(tokio::spawn(async move {
+            if let Err(e) = service(service_socket,Reply { socket: reply_socket, ports },&service_announcer,&service_verifier,&service_guard,service_tx,service_responded).await {
+                info!("discovery service stopped: {}", e);
+            }
+        }));
+        // the answers to our own requests
+        let answer_socket = self.send_socket.clone();
+        let service_announcer = announcer.clone();
+        let service_verifier = verifier.clone();
+        let service_guard = guard.clone();
+        let service_tx = tx.clone();
+        let service_responded = responded.clone();
+        self.tasks.push(tokio::spawn(async move {
+            if let Err(e) = service(answer_socket.clone(),Reply { socket: answer_socket, ports },&service_announcer,&service_verifier,&service_guard,service_tx,service_responded).await {
+                info!("discovery answer service stopped: {}", e);
+            }
+        }));
+        self.tasks.push(tokio::spawn(async move {
+            receive_handle(recv_socket,ports,&recv_announcer,rx).await;
+        }));
+
+        if let Some(broadcast_socket) = self.broadcast_socket.clone() {
+            // requests arrive on the broadcast socket, replies go out unicast
+            let reply_socket = self.send_socket.clone();
+            let service_announcer = announcer.clone();
+            let service_responded = responded.clone();
+            self.tasks.push(tokio::spawn(async move {
+                if let Err(e) = service(broadcast_socket,Reply { socket: reply_socket, ports },&service_announcer,&verifier,&guard,tx,service_responded).await {
+                    info!("broadcast discovery service stopped: {}", e);
+                }
+            }));
+
+        }
+
+        // multicast again every announce_secs, broadcast along while multicast goes unanswered
+        let socket = self.send_socket.clone();
+        let multicast = self.config.multicast_ip.to_string();
+        let broadcast = self.broadcast_socket.as_ref().map(|_| self.broadcast.to_string());
+        let mode = self.config.broadcast;
+        let every = std::time::Duration::from_secs(self.config.announce_secs);
+        let wait = std::time::Duration::from_secs(self.config.broadcast_fallback_secs).min(every);
+        let announcer = announcer.clone();
+        self.tasks.push(tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(every);
+            // the first request went out above
+            ticks.tick().await;
+            loop {
+                if let Some(broadcast) = &broadcast {
+                    let fallback = match mode {
+                        BroadcastMode::Auto => {
+                            tokio::time::sleep(wait).await;
+                            // each round decides again
+                            let fallback = !responded.swap(false, Ordering::Relaxed);
+                            if fallback {
+                                info!("no multicast response, fall back to broadcast {}", broadcast);
+                            }
+                            fallback
+                        },
+                        _ => true,
+                    };
+                    if fallback && announcer.visibility().announces() {
+                        if let Err(e) = send_discovery(&socket,broadcast,ports,&announcer).await {
+                            info!("send broadcast discovery to {} failed: {}", broadcast, e);
+                        }
+                    }
+                }
+                ticks.tick().await;
+                if announcer.visibility().announces() {
+                    if let Err(e) = send_discovery(&socket,&multicast,ports,&announcer).await {
+                        info!("send discovery to {} failed: {}", multicast, e);
+                    }
+                }
+            }
+        }));
 
         Ok(add_tx)
     }
+
+    /// tell the devices on this network that this one goes offline, where it announces itself
+    pub async fn goodbye(&self, announcer: &Announcer) {
+        if !announcer.visibility().announces() {
+            return;
+        }
+        let data = match serde_json::to_string(&announcer.goodbye(self.ports.accepter)) {
+            Ok(data) => data,
+            Err(e) => {
+                info!("can't encode goodbye: {}", e);
+                return;
+            },
+        };
+        let mut targets = vec![self.config.multicast_ip];
+        if self.broadcast_socket.is_some() {
+            targets.push(self.broadcast);
+        }
+        for ip in targets {
+            debug!("send goodbye to {}:{}", ip, self.ports.discovery);
+            if let Err(e) = self.send_socket.send_to(data.as_bytes(), (ip, self.ports.discovery)).await {
+                info!("send goodbye to {} failed: {}", ip, e);
+            }
+        }
+    }
+}
+
+impl Drop for Discovery {
+    /// stop all background tasks so the sockets get closed
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
 }
 
-async fn service(socket: Arc<tokio::net::UdpSocket>, dev: &Device, tx: tokio::sync::mpsc::Sender<crate::device::RemoteTcpDevice>) -> io::Result<()> {
-    let mut buf = Vec::<u8>::new();
-    let local_addr = socket.local_addr().unwrap();
+/// receive discovery requests on `socket`, answer the ones asking for an ack through `reply`.
+/// a bad datagram or a failed reply is logged, it ends once nobody takes the devices found.
+async fn service(socket: Arc<tokio::net::UdpSocket>, reply: Reply, announcer: &Announcer, verifier: &Verifier, guard: &FloodGuard, tx: tokio::sync::mpsc::Sender<crate::device::RemoteTcpDevice>, responded: Arc<AtomicBool>) -> Result<()> {
+    let local_addr = socket.local_addr()?;
     info!("local addr: {}",local_addr);
-    let host_device = dev.clone();
+    let host_device = announcer.device().clone();
+    // one byte more than allowed, so too long datagrams are noticed
+    let mut data = vec![0; MAX_DATAGRAM + 1];
     loop {
-        let mut data = [0; 1024];
-        let (lens, addr) = socket.recv_from(&mut data).await.expect("disconnect.");
-        buf.append(&mut data[..lens].to_vec());
-        if let Ok(discovery) = serde_json::from_slice::<DiscoveryReq>(&buf){
-            buf.clear();
-            if host_device.id == discovery.device.id.clone() {
+        let (lens, addr) = match socket.recv_from(&mut data).await {
+            Ok(received) => received,
+            Err(e) => {
+                // e.g. an icmp error of an earlier reply
+                debug!("receive on {} failed: {}", local_addr, e);
                 continue;
-            }
+            },
+        };
+        if lens > MAX_DATAGRAM {
+            debug!("drop oversized datagram from {}", addr);
+            continue;
+        }
+        if !guard.allow(addr.ip()) {
+            debug!("drop discovery from {}: rate limited", addr);
+            continue;
+        }
+        let discovery = match serde_json::from_slice::<DiscoveryReq>(&data[..lens]) {
+            Ok(discovery) => discovery,
+            Err(e) => {
+                debug!("ignore datagram from {}: {}", addr, e);
+                continue;
+            },
+        };
+        if host_device.id == discovery.device.id.clone() {
+            continue;
+        }
+        let fingerprint = match verifier.check(&discovery) {
+            Ok(fingerprint) => fingerprint,
+            Err(e) => {
+                info!("drop announcement of {} from {}: {}", discovery.device.id, addr, e);
+                continue;
+            },
+        };
+        if discovery.bye && fingerprint.is_none() {
+            info!("drop unsigned goodbye of {} from {}", discovery.device.id, addr);
+            continue;
+        }
+        responded.store(true, Ordering::Relaxed);
+        let pinned = verifier.is_pinned(&discovery.device.id);
+        let rooms = announcer.rooms_of(&discovery);
+        if !pinned && !announcer.in_scope(&discovery, &rooms) {
+            debug!("ignore {}: not in our rooms", discovery.device.id);
+            continue;
+        }
 
-            debug!("send for notify");
-            let remote_device = RemoteTcpDevice { addr: SocketAddr::new(addr.ip(), discovery.port), device: discovery.device };
-            tx.send(remote_device).await.expect("send failed");
-            
-            // for ack
-            if discovery.ack {
-                let discovery_resp = DiscoveryReq::new(&host_device, accepter::TCP_ACCEPTER_PORT , false);
-                let data = serde_json::to_string(&discovery_resp).unwrap();
-                socket.send_to(data.as_bytes(), format!("{}:{}",addr.ip(),UDP_PORT)).await.unwrap();
+        debug!("send for notify");
+        if !discovery.is_compatible() {
+            info!("{} speaks protocol version {}, we speak {}", discovery.device.name, discovery.version, VERSION);
+        }
+        let ack = discovery.ack && !discovery.bye
+            && announcer.visibility().answers(pinned)
+            && guard.should_reply(addr.ip(), &discovery.device.id);
+        let tcp_addr = SocketAddr::new(addr.ip(), discovery.port);
+        let mut remote_device = discovery.into_remote(tcp_addr);
+        remote_device.fingerprint = fingerprint;
+        remote_device.rooms = rooms;
+        if tx.send(remote_device).await.is_err() {
+            debug!("controller is gone, stop discovery on {}", local_addr);
+            return Ok(());
+        }
+
+        // for ack
+        if ack {
+            let discovery_resp = announcer.request(reply.ports.accepter, false);
+            let data = serde_json::to_string(&discovery_resp)?;
+            // older versions send from the discovery port, newer ones from their own
+            if let Err(e) = reply.socket.send_to(data.as_bytes(), addr).await {
+                info!("reply to {} failed: {}", addr.ip(), e);
             }
         }
         debug!("one device has discoveryed.");
     }
 }
 
-async fn receive_handle(socket: Arc<tokio::net::UdpSocket>, dev: &Device,mut rx: tokio::sync::mpsc::Receiver<String>) -> io::Result<()> {
-    let host_device = dev.clone();
-    loop{
-        if let Some(ip) = rx.recv().await {
-            debug!("do add device: {}", ip);
-            //do add device
-            send_discovery(&socket,&ip,&host_device).await?;
+/// bind `addr` so other instances on the host can bind it too, multicast and broadcast
+/// requests reach all of them
+fn bind_shared(addr: SocketAddr) -> std::io::Result<UdpSocket> {
+    let socket = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::DGRAM, Some(socket2::Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// probe the addresses sent to `rx` until the discovery is dropped
+async fn receive_handle(socket: Arc<tokio::net::UdpSocket>, ports: Ports, announcer: &Announcer,mut rx: tokio::sync::mpsc::Receiver<String>) {
+    while let Some(ip) = rx.recv().await {
+        debug!("do add device: {}", ip);
+        if !announcer.visibility().probes() {
+            debug!("hidden, don't probe {}", ip);
+            continue;
+        }
+        //do add device
+        if let Err(e) = send_discovery(&socket,&ip,ports,announcer).await {
+            info!("probe {} failed: {}", ip, e);
         }
     }
 }
 
-async fn send_discovery(socket: &Arc<tokio::net::UdpSocket>,addr:&str,dev: &Device) -> std::io::Result<()> {
-    let discovery_req = DiscoveryReq::new(dev, accepter::TCP_ACCEPTER_PORT, true);
+async fn send_discovery(socket: &Arc<tokio::net::UdpSocket>,addr:&str,ports: Ports,announcer: &Announcer) -> std::io::Result<()> {
+    let discovery_req = announcer.request(ports.accepter, true);
     let data = serde_json::to_string(&discovery_req)?;
-    debug!("send discovery request to {}:{}",addr,UDP_PORT);
-    socket.send_to(data.as_bytes(), format!("{}:{}",addr,UDP_PORT)).await?;
+    debug!("send discovery request to {}:{}",addr,ports.discovery);
+    socket.send_to(data.as_bytes(), format!("{}:{}",addr,ports.discovery)).await?;
     //stream.write(src)
     debug!("send end...");
     Ok(())
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// datagrams longer than this are dropped without parsing
+pub const MAX_DATAGRAM: usize = 8192;
+/// a device asking again within this time gets no second ack
+const REPLY_INTERVAL: Duration = Duration::from_secs(2);
+/// forget sources and replies idle for this long once the tables get big
+const IDLE: Duration = Duration::from_secs(30);
+/// sources and replies remembered, beyond it the least recently seen one is dropped
+const MAX_ENTRIES: usize = 4096;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Per source limits of the discovery service: a token bucket on the received
+/// requests and at most one ack per device every `REPLY_INTERVAL`.
+pub struct FloodGuard {
+    /// requests per second accepted from one source
+    rate: f64,
+    /// requests accepted at once from an idle source
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+    replies: Mutex<HashMap<(IpAddr, String), Instant>>,
+}
+
+impl FloodGuard {
+    pub fn new(rate: u32) -> Self {
+        let rate = rate.max(1) as f64;
+        Self {
+            rate,
+            burst: rate * 2.0,
+            buckets: Mutex::new(HashMap::new()),
+            replies: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// take a token for a request from `source`, false when it sends too fast
+    pub fn allow(&self, source: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_ENTRIES && !buckets.contains_key(&source) {
+            buckets.retain(|_, b| now.duration_since(b.updated) < IDLE);
+            // spoofed sources can keep all of them busy
+            make_room(&mut buckets, |b| b.updated);
+        }
+        let bucket = buckets.entry(source).or_insert(Bucket { tokens: self.burst, updated: now });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * self.rate).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// whether to ack the request of `id` from `source`, repeated requests are answered once
+    pub fn should_reply(&self, source: IpAddr, id: &str) -> bool {
+        let now = Instant::now();
+        let mut replies = self.replies.lock().unwrap();
+        let key = (source, id.to_string());
+        if replies.len() >= MAX_ENTRIES && !replies.contains_key(&key) {
+            replies.retain(|_, at| now.duration_since(*at) < IDLE);
+            make_room(&mut replies, |at| *at);
+        }
+        match replies.get(&key) {
+            Some(at) if now.duration_since(*at) < REPLY_INTERVAL => false,
+            _ => {
+                replies.insert(key, now);
+                true
+            },
+        }
+    }
+}
+
+/// drop the least recently seen entries until one more fits into `MAX_ENTRIES`
+fn make_room<K: Clone + Eq + std::hash::Hash, V>(map: &mut HashMap<K, V>, seen: impl Fn(&V) -> Instant) {
+    while map.len() >= MAX_ENTRIES {
+        let oldest = match map.iter().min_by_key(|(_, v)| seen(v)) {
+            Some((k, _)) => k.clone(),
+            None => return,
+        };
+        map.remove(&oldest);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn ip(n: u32) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + n))
+    }
+
+    #[test]
+    fn allow_burst() {
+        let guard = FloodGuard::new(5);
+        for _ in 0..10 {
+            assert!(guard.allow(ip(1)));
+        }
+        assert!(!guard.allow(ip(1)));
+        // other sources have their own bucket
+        assert!(guard.allow(ip(2)));
+    }
+
+    #[test]
+    fn reply_once() {
+        let guard = FloodGuard::new(5);
+        assert!(guard.should_reply(ip(1), "a"));
+        assert!(!guard.should_reply(ip(1), "a"));
+        assert!(guard.should_reply(ip(1), "b"));
+        assert!(guard.should_reply(ip(2), "a"));
+    }
+
+    #[test]
+    fn entries_are_capped() {
+        let guard = FloodGuard::new(5);
+        for n in 0..MAX_ENTRIES as u32 * 2 {
+            assert!(guard.allow(ip(n)));
+            assert!(guard.should_reply(ip(n), "a"));
+        }
+        assert_eq!(guard.buckets.lock().unwrap().len(), MAX_ENTRIES);
+        assert_eq!(guard.replies.lock().unwrap().len(), MAX_ENTRIES);
+        // a known source keeps its state
+        let last = ip(MAX_ENTRIES as u32 * 2 - 1);
+        assert!(!guard.should_reply(last, "a"));
+    }
+}
@@ -0,0 +1,137 @@
+use tokio::io::{AsyncRead, AsyncWrite};
+use rsa::RsaPrivateKey;
+use log::info;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::config::{ReceiveConfig, DEFAULT_BUFFER_SIZE};
+use crate::error::{Error, Result};
+use crate::file_meta::MetaList;
+use crate::identity;
+use super::accepter::{Accepter, Handshake};
+use super::command::Event;
+use super::secure;
+use super::subscriber::Subscribers;
+use super::transfers::{TransferState, Transfers};
+
+/// how long an offer waits for the user before it is declined
+const OFFER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// receives incoming transfers into the download directory when the accept rules allow it
+#[derive(Clone)]
+pub struct Receiver {
+    config: ReceiveConfig,
+    private_key: RsaPrivateKey,
+    /// fingerprints of pinned devices by id
+    pins: Arc<Mutex<HashMap<String, String>>>,
+    transfers: Transfers,
+    subscribers: Subscribers,
+    /// bytes of a file read and written at once
+    buffer_size: usize,
+}
+
+impl Receiver {
+    /// incoming transfers are listed in `transfers` and published to `subscribers`
+    pub fn new(config: ReceiveConfig, private_key: RsaPrivateKey, pins: Arc<Mutex<HashMap<String, String>>>, transfers: Transfers, subscribers: Subscribers) -> Self {
+        Self { config, private_key, pins, transfers, subscribers, buffer_size: DEFAULT_BUFFER_SIZE }
+    }
+
+    pub fn with_buffer_size(mut self, buffer_size: usize) -> Self {
+        self.buffer_size = buffer_size;
+        self
+    }
+
+    /// receive one transfer after the key exchange, `from` names the sender in the log.
+    /// returns the files received, `None` when the transfer was declined.
+    pub async fn receive<T>(&self, stream: T, from: &str, handshake: Handshake) -> Result<Option<MetaList>>
+    where T: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+        let fingerprint = identity::fingerprint(&handshake.public_key);
+        if handshake.encrypted {
+            let stream = secure::accept(stream, &self.private_key).await?;
+            self.receive_from(stream, from, &fingerprint).await
+        } else {
+            self.receive_from(stream, from, &fingerprint).await
+        }
+    }
+
+    async fn receive_from<T>(&self, mut stream: T, from: &str, fingerprint: &str) -> Result<Option<MetaList>>
+    where T: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+        let device = self.pins.lock().unwrap().iter().find(|(_, fp)| *fp == fingerprint).map(|(id, _)| id.clone());
+        let pinned = device.is_some();
+        let meta_list = Accepter::recv_meta(&mut stream).await?;
+        // `recv_meta` made sure it doesn't overflow
+        let size = meta_list.total_size().unwrap_or(u64::MAX);
+        let names = meta_list.files.iter().map(|f| PathBuf::from(&f.name)).collect();
+        let transfer = self.transfers.add_incoming(device, from, fingerprint, names, size);
+        let id = transfer.id;
+
+        let accepted = if self.config.accepts(fingerprint, pinned, size) {
+            true
+        } else if self.config.asks(size) && self.subscribers.listening() {
+            let answer = self.transfers.offer(id);
+            info!("offer {} files, {} bytes from {} {}", meta_list.files.len(), size, from, fingerprint);
+            self.subscribers.publish(Event::IncomingOffer { transfer });
+            // no answer in time, or the offer was cancelled
+            matches!(tokio::time::timeout(OFFER_TIMEOUT, answer).await, Ok(Ok(true)))
+        } else {
+            false
+        };
+        info!("{} {} files, {} bytes from {} {}",
+            if accepted { "accept" } else { "decline" }, meta_list.files.len(), size, from, fingerprint);
+        if !accepted {
+            self.finish(id, TransferState::Declined);
+            Accepter::decline(&mut stream).await?;
+            return Ok(None);
+        }
+
+        self.transfers.start(id);
+        if let Some(transfer) = self.transfers.get(id) {
+            self.subscribers.publish(Event::TransferStarted { transfer });
+        }
+        let dir = self.config.download_dir.clone().unwrap_or_else(|| PathBuf::from("."));
+        let report = self.transfers.reporter(id, self.subscribers.clone());
+        let buffer_size = self.buffer_size;
+        // its own task, so cancelling the transfer can abort it
+        let task = {
+            let dir = dir.clone();
+            tokio::spawn(async move {
+                Accepter::recv_accepted(&mut stream, &dir, &meta_list, buffer_size, report).await.map(|_| meta_list)
+            })
+        };
+        self.transfers.set_task(id, task.abort_handle());
+        let received = match task.await {
+            Ok(received) => received,
+            Err(e) if e.is_cancelled() => Err(Error::Cancelled),
+            Err(e) => Err(Error::Io(std::io::Error::other(e.to_string()))),
+        };
+        match &received {
+            Ok(list) => {
+                for file in &list.files {
+                    info!("received {} ({} bytes) from {} into {}", file.name, file.size, from, dir.display());
+                }
+                self.finish(id, TransferState::Done);
+            },
+            Err(e) => self.finish(id, TransferState::Failed(e.to_string())),
+        }
+        received.map(Some)
+    }
+
+    /// log a connection from `from` that failed and tell the front ends,
+    /// a cancelled transfer was reported when it ended
+    pub fn report(&self, from: &str, e: &Error) {
+        if let Error::Cancelled = e {
+            return;
+        }
+        info!("receive from {} failed: {}", from, e);
+        self.subscribers.publish(Event::error(format!("receive from {} failed: {}", from, e)));
+    }
+
+    /// end transfer `id` unless it was cancelled already
+    fn finish(&self, id: u64, state: TransferState) {
+        if let Some(transfer) = self.transfers.finish(id, state) {
+            self.subscribers.publish(Event::TransferFinished { transfer });
+        }
+    }
+}
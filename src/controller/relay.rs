@@ -0,0 +1,181 @@
+use tokio::net::TcpStream;
+use tokio::io;
+use log::{debug, info};
+use rand::RngCore;
+use rsa::RsaPublicKey;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use std::sync::Arc;
+
+use crate::device::RemoteTcpDevice;
+use crate::error::{Error, Result};
+use crate::relay_proto::{RelayMessage, read_message, write_message};
+use super::accepter::Accepter;
+use super::receiver::Receiver;
+use super::discoverer::DiscoveryReq;
+use super::signing::{Announcer, Verifier};
+
+/// wait before reconnecting a lost control connection
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// announce again so the other side keeps seeing us
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Keep a control connection to the relay: announce this device, report the devices
+/// announced through the relay and receive the transfers they send.
+pub async fn run(relay: SocketAddr, announcer: Announcer, verifier: Arc<Verifier>, public_key: RsaPublicKey, receiver: Receiver, tx: tokio::sync::mpsc::Sender<RemoteTcpDevice>) {
+    loop {
+        if let Err(e) = control(relay, &announcer, &verifier, &public_key, &receiver, &tx).await {
+            info!("relay {} disconnected: {}", relay, e);
+        }
+        if tx.is_closed() {
+            break;
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn control(relay: SocketAddr, announcer: &Announcer, verifier: &Verifier, public_key: &RsaPublicKey, receiver: &Receiver, tx: &tokio::sync::mpsc::Sender<RemoteTcpDevice>) -> io::Result<()> {
+    let host = announcer.device();
+    let mut stream = TcpStream::connect(relay).await?;
+    write_message(&mut stream, &RelayMessage::Register { id: host.id.clone() }).await?;
+    match read_message(&mut stream).await? {
+        RelayMessage::Registered => info!("registered at relay {}", relay),
+        RelayMessage::Error { message } => return Err(io::Error::other(message)),
+        _ => return Err(invalid("unexpected relay message")),
+    }
+    let (mut reader, mut writer) = stream.into_split();
+
+    let (announce_tx, mut announce_rx) = tokio::sync::mpsc::channel::<RelayMessage>(8);
+    let announce = announcer.clone();
+    let writer_task = tokio::spawn(async move {
+        let mut timer = tokio::time::interval(ANNOUNCE_INTERVAL);
+        loop {
+            let msg = tokio::select! {
+                _ = timer.tick() => {
+                    if !announce.visibility().announces() {
+                        continue;
+                    }
+                    discovery(&announce, None, true)
+                },
+                msg = announce_rx.recv() => match msg {
+                    Some(msg) => msg,
+                    None => break,
+                },
+            };
+            if write_message(&mut writer, &msg).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let result = loop {
+        let msg = match read_message(&mut reader).await {
+            Ok(msg) => msg,
+            Err(e) => break Err(e),
+        };
+        match msg {
+            RelayMessage::Discovery { from: Some(from), data, .. } => {
+                let req: DiscoveryReq = match serde_json::from_value(data) {
+                    Ok(req) => req,
+                    Err(e) => {
+                        debug!("ignore discovery from {}: {}", from, e);
+                        continue;
+                    },
+                };
+                if req.device.id == host.id || req.device.id != from {
+                    continue;
+                }
+                let fingerprint = match verifier.check(&req) {
+                    Ok(Some(fingerprint)) => fingerprint,
+                    // the relay could swap the keys of a device without one
+                    Ok(None) => {
+                        info!("drop unsigned announcement of {} through relay", from);
+                        continue;
+                    },
+                    Err(e) => {
+                        info!("drop announcement of {} through relay: {}", from, e);
+                        continue;
+                    },
+                };
+                let pinned = verifier.is_pinned(&from);
+                let rooms = announcer.rooms_of(&req);
+                if !pinned && !announcer.in_scope(&req, &rooms) {
+                    continue;
+                }
+                if req.ack && announcer.visibility().answers(pinned) {
+                    let _ = announce_tx.send(discovery(announcer, Some(from), false)).await;
+                }
+                let mut device = req.into_remote(relay);
+                device.relayed = true;
+                // the relay is no address of the device
+                device.routes.clear();
+                device.fingerprint = Some(fingerprint);
+                device.rooms = rooms;
+                if tx.send(device).await.is_err() {
+                    break Ok(());
+                }
+            },
+            RelayMessage::Incoming { from, session } => {
+                let public_key = public_key.clone();
+                let receiver = receiver.clone();
+                tokio::spawn(async move {
+                    info!("receive files from {} through relay", from);
+                    if let Err(e) = receive(relay, session, &from, &public_key, &receiver).await {
+                        receiver.report(&format!("{} through the relay", from), &e);
+                    }
+                });
+            },
+            _ => {},
+        }
+    };
+    writer_task.abort();
+    result
+}
+
+fn discovery(announcer: &Announcer, to: Option<String>, ack: bool) -> RelayMessage {
+    let req = announcer.request(0, ack);
+    RelayMessage::Discovery {
+        from: None,
+        to,
+        data: serde_json::to_value(&req).expect("discovery request is serializable"),
+    }
+}
+
+/// open the data connection for an incoming transfer and receive the files
+async fn receive(relay: SocketAddr, session: String, from: &str, public_key: &RsaPublicKey, receiver: &Receiver) -> Result<()> {
+    let mut stream = TcpStream::connect(relay).await?;
+    write_message(&mut stream, &RelayMessage::Accept { session }).await?;
+    expect_connected(&mut stream).await?;
+    let handshake = Accepter::handshake(&mut stream, public_key).await?;
+    if !handshake.encrypted {
+        return Err(Error::protocol("transfers through the relay must be encrypted"));
+    }
+    receiver.receive(stream, &format!("{} through the relay", from), handshake).await?;
+    Ok(())
+}
+
+/// open a data connection to `to`, the returned stream reaches the peer's accepter
+pub async fn connect(relay: SocketAddr, from: &str, to: &str) -> io::Result<TcpStream> {
+    let mut session = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut session);
+    let session = session.iter().map(|x| format!("{:02x}", x)).collect::<String>();
+
+    let mut stream = TcpStream::connect(relay).await?;
+    stream.set_nodelay(true)?;
+    write_message(&mut stream, &RelayMessage::Connect { from: from.to_string(), to: to.to_string(), session }).await?;
+    expect_connected(&mut stream).await?;
+    Ok(stream)
+}
+
+async fn expect_connected(stream: &mut TcpStream) -> io::Result<()> {
+    match read_message(stream).await? {
+        RelayMessage::Connected => Ok(()),
+        RelayMessage::Error { message } => Err(io::Error::new(io::ErrorKind::ConnectionRefused, message)),
+        _ => Err(invalid("unexpected relay message")),
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
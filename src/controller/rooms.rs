@@ -0,0 +1,26 @@
+use sha2::{Digest, Sha256};
+
+/// hex chars kept of a room tag
+const TAG_LEN: usize = 32;
+
+/// Hash of `room` keyed with the nonce of one announcement, so only members can
+/// match it and announcements of the same room can't be linked.
+pub fn tag(room: &str, nonce: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(b"rsdrop-room\n");
+    hasher.update(room.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(nonce.as_bytes());
+    let digest = hasher.finalize();
+    let mut tag: String = digest.iter().map(|x| format!("{:02x}", x)).collect();
+    tag.truncate(TAG_LEN);
+    tag
+}
+
+/// the rooms of `rooms` one of the `tags` was made from
+pub fn matching(rooms: &[String], tags: &[String], nonce: &str) -> Vec<String> {
+    rooms.iter()
+        .filter(|room| tags.contains(&tag(room, nonce)))
+        .cloned()
+        .collect()
+}
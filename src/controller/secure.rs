@@ -0,0 +1,237 @@
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncReadExt, AsyncWriteExt, DuplexStream, ReadBuf};
+use rsa::{Oaep, PublicKey, RsaPrivateKey, RsaPublicKey};
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce};
+use chacha20poly1305::aead::Aead;
+use rand::RngCore;
+use sha2::Sha256;
+use log::debug;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{ready, Context, Poll};
+
+use crate::error::{Error, Result};
+
+/// plaintext bytes per frame
+const FRAME_SIZE: usize = 16 * 1024;
+/// poly1305 tag added to every frame
+const TAG_SIZE: usize = 16;
+
+/// Sender side: make a session key, send it encrypted with the public key of the
+/// receiver and wrap the stream so everything after it is encrypted.
+pub async fn offer<T>(mut stream: T, peer_key: &RsaPublicKey) -> Result<SecureStream>
+where T: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    let mut key = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut key);
+    let encrypted = peer_key.encrypt(&mut rand::thread_rng(), Oaep::new::<Sha256>(), &key)
+        .map_err(Error::crypto)?;
+    stream.write_u16(encrypted.len() as u16).await?;
+    stream.write_all(&encrypted).await?;
+    debug!("session key sent");
+    Ok(wrap(stream, key, true))
+}
+
+/// Receiver side: read the session key sent by `offer` and wrap the stream.
+pub async fn accept<T>(mut stream: T, private_key: &RsaPrivateKey) -> Result<SecureStream>
+where T: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    let len = stream.read_u16().await? as usize;
+    let mut encrypted = vec![0u8; len];
+    stream.read_exact(&mut encrypted).await?;
+    let key = private_key.decrypt(Oaep::new::<Sha256>(), &encrypted)
+        .map_err(Error::crypto)?;
+    let key: [u8; 32] = key.try_into()
+        .map_err(|_| Error::crypto("session key has a wrong size"))?;
+    debug!("session key received");
+    Ok(wrap(stream, key, false))
+}
+
+/// Frames are `u32 length | ciphertext`, the nonce is a per direction counter so
+/// both sides can use the same key. A frame without data ends the stream, so whoever
+/// carries it, e.g. the relay, can't cut it short unnoticed.
+fn wrap<T>(stream: T, key: [u8; 32], initiator: bool) -> SecureStream
+where T: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    let (local, remote) = io::duplex(FRAME_SIZE * 2);
+    let (mut plain_rx, mut plain_tx) = io::split(remote);
+    let (mut cipher_rx, mut cipher_tx) = io::split(stream);
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+    let (send_dir, recv_dir) = if initiator { (0u8, 1u8) } else { (1u8, 0u8) };
+    let ended = Arc::new(AtomicBool::new(false));
+
+    let send_cipher = cipher.clone();
+    let sender = tokio::spawn(async move {
+        let mut counter = 0u64;
+        let mut buf = vec![0u8; FRAME_SIZE];
+        loop {
+            // data frames are never empty, 0 is the end of the plaintext
+            let lens = plain_rx.read(&mut buf).await?;
+            let frame = send_cipher.encrypt(&nonce(send_dir, counter), &buf[..lens])
+                .map_err(|_| io::Error::other("encrypting a frame failed"))?;
+            counter += 1;
+            cipher_tx.write_u32(frame.len() as u32).await?;
+            cipher_tx.write_all(&frame).await?;
+            if lens == 0 {
+                break;
+            }
+        }
+        cipher_tx.shutdown().await
+    });
+
+    let receiver_ended = ended.clone();
+    tokio::spawn(async move {
+        let mut counter = 0u64;
+        loop {
+            let len = match cipher_rx.read_u32().await {
+                Ok(len) if len as usize <= FRAME_SIZE + TAG_SIZE => len as usize,
+                _ => break,
+            };
+            let mut frame = vec![0u8; len];
+            if cipher_rx.read_exact(&mut frame).await.is_err() {
+                break;
+            }
+            let data = match cipher.decrypt(&nonce(recv_dir, counter), frame.as_slice()) {
+                Ok(data) => data,
+                Err(_) => {
+                    debug!("drop session, frame {} failed verification", counter);
+                    break;
+                },
+            };
+            counter += 1;
+            if data.is_empty() {
+                receiver_ended.store(true, Ordering::Release);
+                break;
+            }
+            if plain_tx.write_all(&data).await.is_err() {
+                break;
+            }
+        }
+        let _ = plain_tx.shutdown().await;
+    });
+
+    SecureStream { inner: local, ended, sender: Some(sender) }
+}
+
+/// The plaintext side of a session. Reading the end fails unless the peer sent its end
+/// frame, shutting down waits until ours is sent.
+pub struct SecureStream {
+    inner: DuplexStream,
+    /// the end frame of the peer arrived
+    ended: Arc<AtomicBool>,
+    /// encrypts and sends what is written, done once the end frame is out
+    sender: Option<tokio::task::JoinHandle<io::Result<()>>>,
+}
+
+impl AsyncRead for SecureStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        if buf.filled().len() == filled && buf.remaining() > 0 && !self.ended.load(Ordering::Acquire) {
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::UnexpectedEof, "session ended without its end frame")));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for SecureStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(Pin::new(&mut self.inner).poll_shutdown(cx))?;
+        let result = match self.sender.as_mut() {
+            Some(sender) => match ready!(Pin::new(sender).poll(cx)) {
+                Ok(result) => result,
+                Err(e) => Err(io::Error::other(e.to_string())),
+            },
+            None => Ok(()),
+        };
+        self.sender = None;
+        Poll::Ready(result)
+    }
+}
+
+fn nonce(direction: u8, counter: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[0] = direction;
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    *Nonce::from_slice(&nonce)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [7; 32];
+
+    /// what `wrap` sends for `data`, including the end frame
+    async fn sealed(data: &[u8]) -> Vec<u8> {
+        let (wire, mut peer) = io::duplex(1024 * 1024);
+        let mut stream = wrap(wire, KEY, true);
+        stream.write_all(data).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut out = Vec::new();
+        peer.read_to_end(&mut out).await.unwrap();
+        out
+    }
+
+    /// what the receiving side of `wrap` reads from `wire`
+    async fn opened(wire: Vec<u8>) -> io::Result<Vec<u8>> {
+        let (local, mut peer) = io::duplex(1024 * 1024);
+        let mut stream = wrap(local, KEY, false);
+        peer.write_all(&wire).await.unwrap();
+        peer.shutdown().await.unwrap();
+        let mut out = Vec::new();
+        stream.read_to_end(&mut out).await.map(|_| out)
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let data: Vec<u8> = (0..FRAME_SIZE * 3 + 5).map(|i| i as u8).collect();
+        let wire = sealed(&data).await;
+        assert_eq!(opened(wire).await.unwrap(), data);
+        assert_eq!(opened(sealed(b"").await).await.unwrap(), b"");
+    }
+
+    #[tokio::test]
+    async fn both_directions() {
+        let (a, b) = io::duplex(64 * 1024);
+        let mut a = wrap(a, KEY, true);
+        let mut b = wrap(b, KEY, false);
+        a.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        b.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        b.write_all(b"pong").await.unwrap();
+        a.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+    }
+
+    #[tokio::test]
+    async fn cut_off_stream_fails() {
+        let wire = sealed(b"some file data").await;
+        // without the end frame, 4 bytes length and the tag of an empty frame
+        let cut = wire[..wire.len() - 4 - TAG_SIZE].to_vec();
+        let err = opened(cut).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(opened(Vec::new()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn tampered_frame_fails() {
+        let mut wire = sealed(b"some file data").await;
+        wire[6] ^= 1;
+        assert!(opened(wire).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_fails() {
+        let mut wire = ((FRAME_SIZE + TAG_SIZE + 1) as u32).to_be_bytes().to_vec();
+        wire.extend(vec![0u8; FRAME_SIZE + TAG_SIZE + 1]);
+        assert!(opened(wire).await.is_err());
+    }
+}
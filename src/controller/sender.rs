@@ -0,0 +1,120 @@
+use tokio::io;
+use log::{debug, info};
+use rsa::RsaPublicKey;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use pnet::ipnetwork::Ipv4Network;
+
+use crate::device::RemoteTcpDevice;
+use crate::error::{Error, Result};
+use crate::identity;
+use super::connector::ClientConnector;
+use super::discoverer;
+use super::relay;
+
+/// What sending needs from the controller, cheap to clone into a transfer task.
+#[derive(Clone)]
+pub struct Sender {
+    host_id: String,
+    public_key: RsaPublicKey,
+    /// networks of our interfaces when the transfer started
+    networks: Vec<Ipv4Network>,
+    /// the connect time of a route is recorded here
+    devices: Arc<Mutex<Vec<RemoteTcpDevice>>>,
+    buffer_size: usize,
+}
+
+impl Sender {
+    pub fn new(host_id: String, public_key: RsaPublicKey, networks: Vec<Ipv4Network>, devices: Arc<Mutex<Vec<RemoteTcpDevice>>>, buffer_size: usize) -> Self {
+        Self { host_id, public_key, networks, devices, buffer_size }
+    }
+
+    /// send `files` to `device`, directly over its best address or through the relay
+    pub async fn send<F: FnMut(u64, u64)>(&self, device: &RemoteTcpDevice, files: &[PathBuf], progress: F) -> Result<()> {
+        debug!("send file {:?} to {} {}", files, device.device.name, device.addr);
+        if !device.compatible {
+            return Err(Error::protocol(format!("{} speaks an incompatible protocol version {}", device.device.name, device.version)));
+        }
+        // the relay must not see the files, direct transfers are encrypted when the receiver can
+        let encrypt = device.relayed || device.supports(discoverer::CAP_ENCRYPTION);
+        let (conn, peer_key) = if device.relayed {
+            // without a fingerprint to check, the relay could answer with its own key
+            if device.fingerprint.is_none() {
+                return Err(Error::VerificationFailed(format!("{} has no known key, it can't be reached through the relay", device.device.name)));
+            }
+            let stream = relay::connect(device.addr, &self.host_id, &device.device.id).await?;
+            let mut conn = ClientConnector::from_stream(stream);
+            let peer_key = self.exchange_keys(&mut conn, device, encrypt).await?;
+            (conn, peer_key)
+        } else {
+            self.connect_best_route(device, encrypt).await?
+        };
+        let mut conn = conn.with_buffer_size(self.buffer_size);
+        if encrypt {
+            conn = conn.secure(&peer_key).await?;
+        }
+        conn.send_files_with_progress(&files.to_vec(), progress).await?;
+        Ok(())
+    }
+
+    /// connect to the first address of `device` that accepts and has the expected key,
+    /// see `routes_by_preference`. returns the connection and the key of the receiver.
+    async fn connect_best_route(&self, device: &RemoteTcpDevice, encrypt: bool) -> Result<(ClientConnector, RsaPublicKey)> {
+        let routes = device.routes_by_preference(|addr| self.is_local(addr));
+        if routes.is_empty() {
+            return Err(Error::Io(io::Error::new(io::ErrorKind::AddrNotAvailable, format!("no address known for {}", device.device.name))));
+        }
+
+        let mut last_err = None;
+        for route in routes {
+            let start = std::time::Instant::now();
+            let mut conn = match ClientConnector::connect(route.addr).await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    info!("connect to {} on {} failed: {}", device.device.name, route.addr, e);
+                    last_err = Some(e);
+                    continue;
+                },
+            };
+            let rtt = start.elapsed();
+            // another host may have the address now, try the next one
+            match self.exchange_keys(&mut conn, device, encrypt).await {
+                Ok(peer_key) => {
+                    debug!("connected to {} on {} in {:?}", device.device.name, route.addr, rtt);
+                    let mut devices = self.devices.lock().unwrap();
+                    if let Some(r) = devices.iter_mut()
+                        .filter(|d| d.device.id == device.device.id)
+                        .flat_map(|d| d.routes.iter_mut())
+                        .find(|r| r.addr == route.addr) {
+                        r.rtt = Some(rtt);
+                    }
+                    return Ok((conn, peer_key));
+                },
+                Err(e) => {
+                    info!("key exchange with {} on {} failed: {}", device.device.name, route.addr, e);
+                    last_err = Some(e);
+                },
+            }
+        }
+        Err(last_err.expect("at least one route was tried"))
+    }
+
+    /// exchange public keys over `conn`, the receiver must have the fingerprint known for `device`
+    async fn exchange_keys(&self, conn: &mut ClientConnector, device: &RemoteTcpDevice, encrypt: bool) -> Result<RsaPublicKey> {
+        let peer_key = conn.send_public_key(&self.public_key, encrypt).await?;
+        if let Some(expected) = &device.fingerprint {
+            if &identity::fingerprint(&peer_key) != expected {
+                return Err(Error::VerificationFailed(format!("public key of {} does not match its fingerprint", device.device.name)));
+            }
+        }
+        Ok(peer_key)
+    }
+
+    fn is_local(&self, addr: &SocketAddr) -> bool {
+        match addr.ip() {
+            std::net::IpAddr::V4(ip) => self.networks.iter().any(|network| network.contains(ip)),
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,287 @@
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use rsa::pkcs1v15::{Signature, SigningKey, VerifyingKey};
+use rsa::pkcs8::{DecodePublicKey, EncodePublicKey};
+use rsa::signature::{SignatureEncoding, Signer, Verifier as _};
+use base64ct::{Base64, Encoding};
+use rand::RngCore;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::device::Device;
+use crate::identity;
+use crate::settings::{Settings, Visibility};
+use super::discoverer::DiscoveryReq;
+use super::rooms;
+
+/// announcements older or newer than this are dropped
+const MAX_CLOCK_SKEW_MS: u64 = 5 * 60 * 1000;
+/// nonces remembered within `MAX_CLOCK_SKEW_MS`, more announcements are dropped until some expire
+const MAX_NONCES: usize = 16384;
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+/// builds the signed discovery requests of this device
+#[derive(Clone)]
+pub struct Announcer {
+    device: Device,
+    key: SigningKey<Sha256>,
+    /// base64 DER
+    public_key: String,
+    visibility: Arc<Mutex<Visibility>>,
+    /// joined room names
+    rooms: Arc<Mutex<Vec<String>>>,
+}
+
+impl Announcer {
+    pub fn new(device: Device, private_key: &RsaPrivateKey, visibility: Visibility, rooms: Vec<String>) -> Self {
+        let public_key = RsaPublicKey::from(private_key).to_public_key_der().expect("can't covert to der");
+        Self {
+            device,
+            key: SigningKey::<Sha256>::new_with_prefix(private_key.clone()),
+            public_key: Base64::encode_string(public_key.as_bytes()),
+            visibility: Arc::new(Mutex::new(visibility)),
+            rooms: Arc::new(Mutex::new(rooms)),
+        }
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    /// share the visibility with the ui, changes apply to the next request
+    pub fn set_visibility(&mut self, visibility: Arc<Mutex<Visibility>>) {
+        self.visibility = visibility;
+    }
+
+    pub fn visibility(&self) -> Visibility {
+        *self.visibility.lock().unwrap()
+    }
+
+    /// share the joined rooms with the ui
+    pub fn set_rooms(&mut self, rooms: Arc<Mutex<Vec<String>>>) {
+        self.rooms = rooms;
+    }
+
+    /// use the visibility and rooms of `settings` from the next request on
+    pub fn apply(&self, settings: &Settings) {
+        *self.visibility.lock().unwrap() = settings.visibility;
+        *self.rooms.lock().unwrap() = settings.rooms.clone();
+    }
+
+    /// the joined rooms `req` was announced in
+    pub fn rooms_of(&self, req: &DiscoveryReq) -> Vec<String> {
+        rooms::matching(&self.rooms.lock().unwrap(), &req.rooms, &req.nonce)
+    }
+
+    /// without rooms only devices without rooms are shown, else the ones sharing a room
+    pub fn in_scope(&self, req: &DiscoveryReq, matched: &[String]) -> bool {
+        if self.rooms.lock().unwrap().is_empty() {
+            req.rooms.is_empty()
+        } else {
+            !matched.is_empty()
+        }
+    }
+
+    pub fn request(&self, port: u16, ack: bool) -> DiscoveryReq {
+        self.sign(DiscoveryReq::new(&self.device, port, ack))
+    }
+
+    /// tells the other devices this one goes offline
+    pub fn goodbye(&self, port: u16) -> DiscoveryReq {
+        let mut req = DiscoveryReq::new(&self.device, port, false);
+        req.bye = true;
+        self.sign(req)
+    }
+
+    fn sign(&self, mut req: DiscoveryReq) -> DiscoveryReq {
+        let mut nonce = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        req.public_key = Some(self.public_key.clone());
+        req.timestamp = now_ms();
+        req.nonce = nonce.iter().map(|x| format!("{:02x}", x)).collect();
+        req.rooms = self.rooms.lock().unwrap().iter().map(|room| rooms::tag(room, &req.nonce)).collect();
+        let signature = self.key.sign(&req.signed_payload());
+        req.signature = Some(Base64::encode_string(&signature.to_bytes()));
+        req
+    }
+}
+
+/// Checks the signature of received announcements.
+///
+/// Signed announcements must be valid, fresh and not seen before. Unsigned ones,
+/// from versions before signing, are only accepted for devices that are not pinned,
+/// and a pinned device must sign with the key of its pinned fingerprint.
+pub struct Verifier {
+    /// fingerprints of pinned devices by device id
+    pins: Arc<Mutex<HashMap<String, String>>>,
+    /// nonces of the accepted announcements and their timestamps
+    nonces: Mutex<HashMap<String, u64>>,
+}
+
+impl Verifier {
+    pub fn new(pins: Arc<Mutex<HashMap<String, String>>>) -> Self {
+        Self {
+            pins,
+            nonces: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// pinned devices have to sign with the pinned key, so their ids can be trusted
+    pub fn is_pinned(&self, id: &str) -> bool {
+        self.pins.lock().unwrap().contains_key(id)
+    }
+
+    /// returns the fingerprint of the signing key, or `None` for an accepted unsigned announcement
+    pub fn check(&self, req: &DiscoveryReq) -> Result<Option<String>, String> {
+        let pinned = self.pins.lock().unwrap().get(&req.device.id).cloned();
+        let (public_key, signature) = match (&req.public_key, &req.signature) {
+            (Some(public_key), Some(signature)) => (public_key, signature),
+            _ => return match pinned {
+                Some(_) => Err("unsigned announcement for a pinned device".to_string()),
+                None => Ok(None),
+            },
+        };
+
+        let der = Base64::decode_vec(public_key).map_err(|e| format!("invalid public key: {}", e))?;
+        let public_key = RsaPublicKey::from_public_key_der(&der).map_err(|e| format!("invalid public key: {}", e))?;
+        let fingerprint = identity::fingerprint(&public_key);
+        if let Some(expected) = pinned {
+            if expected != fingerprint {
+                return Err("public key does not match the pinned fingerprint".to_string());
+            }
+        }
+
+        let signature = Base64::decode_vec(signature).map_err(|e| format!("invalid signature: {}", e))?;
+        let signature = Signature::try_from(signature.as_slice()).map_err(|e| format!("invalid signature: {}", e))?;
+        VerifyingKey::<Sha256>::new_with_prefix(public_key)
+            .verify(&req.signed_payload(), &signature)
+            .map_err(|_| "signature verification failed".to_string())?;
+
+        let now = now_ms();
+        if req.timestamp.abs_diff(now) > MAX_CLOCK_SKEW_MS {
+            return Err("announcement is too old or from the future".to_string());
+        }
+        let mut nonces = self.nonces.lock().unwrap();
+        nonces.retain(|_, timestamp| timestamp.abs_diff(now) <= MAX_CLOCK_SKEW_MS);
+        if nonces.contains_key(&req.nonce) {
+            return Err("replayed announcement".to_string());
+        }
+        // forgetting one instead would let its announcement be replayed
+        if nonces.len() >= MAX_NONCES {
+            return Err("too many announcements to check for replays".to_string());
+        }
+        nonces.insert(req.nonce.clone(), req.timestamp);
+        Ok(Some(fingerprint))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::OnceLock;
+
+    /// a small key, generating one is slow
+    fn private_key(n: usize) -> &'static RsaPrivateKey {
+        static KEYS: OnceLock<[RsaPrivateKey; 2]> = OnceLock::new();
+        let keys = KEYS.get_or_init(|| {
+            let mut rng = rand::thread_rng();
+            [RsaPrivateKey::new(&mut rng, 1024).unwrap(), RsaPrivateKey::new(&mut rng, 1024).unwrap()]
+        });
+        &keys[n]
+    }
+
+    fn announcer(key: usize) -> Announcer {
+        let device = Device { name: "box".to_string(), r#type: "linux".to_string(), id: "1-2".to_string() };
+        Announcer::new(device, private_key(key), Visibility::Everyone, Vec::new())
+    }
+
+    fn verifier(pins: &[(&str, String)]) -> Verifier {
+        let pins = pins.iter().map(|(id, fp)| (id.to_string(), fp.clone())).collect();
+        Verifier::new(Arc::new(Mutex::new(pins)))
+    }
+
+    fn fingerprint(key: usize) -> String {
+        identity::fingerprint(&RsaPublicKey::from(private_key(key)))
+    }
+
+    #[test]
+    fn check_signed() {
+        let verifier = verifier(&[]);
+        assert_eq!(verifier.check(&announcer(0).request(52638, false)), Ok(Some(fingerprint(0))));
+        assert_eq!(verifier.check(&announcer(0).goodbye(52638)), Ok(Some(fingerprint(0))));
+    }
+
+    #[test]
+    fn check_replay() {
+        let verifier = verifier(&[]);
+        let req = announcer(0).request(52638, false);
+        assert!(verifier.check(&req).is_ok());
+        assert!(verifier.check(&req).is_err());
+    }
+
+    #[test]
+    fn check_expiry() {
+        let verifier = verifier(&[]);
+        // the timestamp is signed, so the announcer has to make the old request
+        let announcer = announcer(0);
+        for timestamp in [now_ms() - MAX_CLOCK_SKEW_MS - 1000, now_ms() + MAX_CLOCK_SKEW_MS + 1000] {
+            let mut req = announcer.request(52638, false);
+            req.timestamp = timestamp;
+            req.signature = Some(Base64::encode_string(&announcer.key.sign(&req.signed_payload()).to_bytes()));
+            assert_eq!(verifier.check(&req), Err("announcement is too old or from the future".to_string()));
+        }
+    }
+
+    #[test]
+    fn check_tampered() {
+        let verifier = verifier(&[]);
+        for change in [
+            |req: &mut DiscoveryReq| req.device.name = "evil".to_string(),
+            |req: &mut DiscoveryReq| req.port = 1,
+            |req: &mut DiscoveryReq| req.min_version += 1,
+            |req: &mut DiscoveryReq| req.bye = true,
+        ] {
+            let mut req = announcer(0).request(52638, false);
+            change(&mut req);
+            assert_eq!(verifier.check(&req), Err("signature verification failed".to_string()));
+        }
+        // a valid signature of another key
+        let mut req = announcer(0).request(52638, false);
+        req.public_key = announcer(1).request(52638, false).public_key;
+        assert!(verifier.check(&req).is_err());
+    }
+
+    #[test]
+    fn check_pins() {
+        let verifier = verifier(&[("1-2", fingerprint(0))]);
+        assert!(verifier.check(&announcer(0).request(52638, false)).is_ok());
+        assert_eq!(verifier.check(&announcer(1).request(52638, false)), Err("public key does not match the pinned fingerprint".to_string()));
+        let mut unsigned = announcer(0).request(52638, false);
+        unsigned.signature = None;
+        assert!(verifier.check(&unsigned).is_err());
+        // unsigned announcements of other devices still pass
+        unsigned.device.id = "3-4".to_string();
+        assert_eq!(verifier.check(&unsigned), Ok(None));
+    }
+
+    #[test]
+    fn check_nonce_cap() {
+        let verifier = verifier(&[]);
+        let req = announcer(0).request(52638, false);
+        let fill = |timestamp: u64| {
+            let mut nonces = verifier.nonces.lock().unwrap();
+            nonces.clear();
+            nonces.extend((0..MAX_NONCES).map(|i| (i.to_string(), timestamp)));
+        };
+        fill(req.timestamp);
+        assert_eq!(verifier.check(&req), Err("too many announcements to check for replays".to_string()));
+        // expired ones make room
+        fill(req.timestamp - MAX_CLOCK_SKEW_MS - 1000);
+        assert!(verifier.check(&req).is_ok());
+    }
+}
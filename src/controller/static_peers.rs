@@ -0,0 +1,141 @@
+use tokio::io;
+use log::{debug, info};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc,Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::StaticPeer;
+use crate::device::{Device, RemoteTcpDevice};
+
+/// time left for the probes to be answered
+const ANSWER_WAIT: Duration = Duration::from_secs(3);
+const STATIC_ID_PREFIX: &str = "static:";
+
+/// resolve `host`, `ip`, `host:port` or `ip:port`, the port is the tcp port of the receiver
+async fn resolve(address: &str, default_port: u16) -> io::Result<Vec<SocketAddr>> {
+    if let Ok(addr) = address.parse::<SocketAddr>() {
+        return Ok(vec![addr]);
+    }
+    if let Ok(ip) = address.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, default_port)]);
+    }
+    let addrs: Vec<SocketAddr> = match address.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() => tokio::net::lookup_host(address).await?.collect(),
+        _ => tokio::net::lookup_host((address, default_port)).await?.collect(),
+    };
+    Ok(addrs.into_iter().filter(|addr| addr.is_ipv4()).collect())
+}
+
+/// the device list entry shown while the peer hasn't answered
+fn placeholder(peer: &StaticPeer, addrs: &[SocketAddr]) -> RemoteTcpDevice {
+    let mut device = RemoteTcpDevice::unreachable(addrs, Device {
+        name: peer.name.clone().unwrap_or_else(|| peer.address.clone()),
+        r#type: "unknown".to_string(),
+        id: format!("{}{}", STATIC_ID_PREFIX, peer.address),
+    });
+    device.pinned = true;
+    device
+}
+
+/// send a unicast discovery request to every static peer, then update their reachability.
+/// `stale` is how long an answer counts as reachable. returns whether the device list changed.
+pub async fn probe(peers: &[StaticPeer], default_port: u16, stale: Duration, disc_txs: &[tokio::sync::mpsc::Sender<String>], devices: &Arc<Mutex<Vec<RemoteTcpDevice>>>) -> bool {
+    let mut resolved = Vec::new();
+    for peer in peers {
+        match resolve(peer.address.trim(), default_port).await {
+            Ok(addrs) if !addrs.is_empty() => resolved.push((peer, addrs)),
+            Ok(_) => info!("static peer {} has no ipv4 address", peer.address),
+            Err(e) => info!("can't resolve static peer {}: {}", peer.address, e),
+        }
+    }
+
+    for addr in resolved.iter().flat_map(|(_, addrs)| addrs.iter()) {
+        for tx in disc_txs {
+            let _ = tx.send(addr.ip().to_string()).await;
+        }
+    }
+    tokio::time::sleep(ANSWER_WAIT).await;
+
+    update(&resolved, stale, &mut devices.lock().unwrap())
+}
+
+/// peers that answered are listed under their own device, the others keep a placeholder
+/// so files can still be sent to the configured address. entries are changed in place,
+/// returns whether any of them changed.
+fn update(resolved: &[(&StaticPeer, Vec<SocketAddr>)], stale: Duration, devices: &mut Vec<RemoteTcpDevice>) -> bool {
+    let now = Instant::now();
+    let mut changed = false;
+    for (peer, addrs) in resolved {
+        let placeholder_id = format!("{}{}", STATIC_ID_PREFIX, peer.address);
+        let mut answered = false;
+        for d in devices.iter_mut().filter(|d| d.device.id != placeholder_id && d.routes.iter().any(|r| addrs.iter().any(|a| a.ip() == r.addr.ip()))) {
+            let reachable = now.duration_since(d.last_seen) < stale;
+            changed |= d.reachable != reachable;
+            d.reachable = reachable;
+            answered |= reachable;
+        }
+        debug!("static peer {} reachable: {}", peer.address, answered);
+
+        let known = devices.iter().position(|d| d.device.id == placeholder_id);
+        match (known, answered) {
+            (Some(i), true) => {
+                devices.remove(i);
+                changed = true;
+            },
+            // the address may resolve differently now
+            (Some(i), false) if !devices[i].routes.iter().map(|r| r.addr).eq(addrs.iter().copied()) => {
+                devices[i] = placeholder(peer, addrs);
+                changed = true;
+            },
+            (None, false) => {
+                devices.push(placeholder(peer, addrs));
+                changed = true;
+            },
+            _ => {},
+        }
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer() -> StaticPeer {
+        StaticPeer { address: "192.0.2.7".to_string(), name: Some("build".to_string()) }
+    }
+
+    #[test]
+    fn update_keeps_placeholder() {
+        let peer = peer();
+        let resolved = vec![(&peer, vec!["192.0.2.7:52638".parse().unwrap()])];
+        let mut devices = Vec::new();
+        assert!(update(&resolved, Duration::from_secs(60), &mut devices));
+        assert_eq!(devices.len(), 1);
+        assert!(!update(&resolved, Duration::from_secs(60), &mut devices));
+        assert_eq!(devices[0].device.id, "static:192.0.2.7");
+
+        let moved = vec![(&peer, vec!["192.0.2.8:52638".parse().unwrap()])];
+        assert!(update(&moved, Duration::from_secs(60), &mut devices));
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].routes[0].addr.ip().to_string(), "192.0.2.8");
+    }
+
+    #[test]
+    fn update_replaces_placeholder_once_answered() {
+        let peer = peer();
+        let addr: SocketAddr = "192.0.2.7:52638".parse().unwrap();
+        let resolved = vec![(&peer, vec![addr])];
+        let mut devices = Vec::new();
+        update(&resolved, Duration::from_secs(60), &mut devices);
+        devices.push(RemoteTcpDevice::new(addr, Device {
+            name: "build01".to_string(),
+            r#type: "linux".to_string(),
+            id: "1".to_string(),
+        }));
+        assert!(update(&resolved, Duration::from_secs(60), &mut devices));
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].device.id, "1");
+        assert!(!update(&resolved, Duration::from_secs(60), &mut devices));
+    }
+}
@@ -0,0 +1,60 @@
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+use super::command::Event;
+
+/// events a slow listener may fall behind before it misses some
+const EVENT_BACKLOG: usize = 64;
+
+/// A front end of the controller. It is told when the shared containers changed,
+/// e.g. to redraw a window, and reads them itself.
+pub trait Subscriber: Send + Sync {
+    /// the device list, share uri or a status message changed
+    fn changed(&self);
+}
+
+/// the subscribers of one controller, cheap to clone into background tasks
+#[derive(Clone)]
+pub struct Subscribers {
+    list: Arc<Mutex<Vec<Arc<dyn Subscriber>>>>,
+    events: broadcast::Sender<Event>,
+}
+
+impl Default for Subscribers {
+    fn default() -> Self {
+        Self {
+            list: Arc::default(),
+            events: broadcast::channel(EVENT_BACKLOG).0,
+        }
+    }
+}
+
+impl Subscribers {
+    pub fn add(&self, subscriber: Arc<dyn Subscriber>) {
+        self.list.lock().unwrap().push(subscriber);
+    }
+
+    /// tell every subscriber something changed
+    pub fn changed(&self) {
+        for subscriber in self.list.lock().unwrap().iter() {
+            subscriber.changed();
+        }
+    }
+
+    /// a new listener for the events published from now on
+    pub fn events(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// whether anyone reads the events, e.g. to answer an offer
+    pub fn listening(&self) -> bool {
+        self.events.receiver_count() > 0
+    }
+
+    /// send `event` to the event listeners and tell the subscribers
+    pub fn publish(&self, event: Event) {
+        // no listener is fine
+        let _ = self.events.send(event);
+        self.changed();
+    }
+}
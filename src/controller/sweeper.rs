@@ -0,0 +1,185 @@
+use tokio::io;
+use log::{debug, info};
+use std::collections::HashSet;
+use std::net::Ipv4Addr;
+use std::sync::{Arc,Mutex};
+use std::time::{Duration, Instant};
+use pnet::ipnetwork::Ipv4Network;
+
+use crate::device::RemoteTcpDevice;
+
+/// probes sent per second
+const PROBE_RATE: u64 = 100;
+/// time left for the last probes to be answered
+const ANSWER_WAIT: Duration = Duration::from_secs(3);
+/// largest number of addresses one sweep may probe (a /16)
+const MAX_TARGETS: usize = 65536;
+/// shortest network prefix accepted, the network of `MAX_TARGETS` addresses
+const MIN_PREFIX: u8 = 16;
+
+/// parse the add box input into the addresses to probe.
+/// accepts `10.2.0.1`, `10.2.0.0/24`, `10.2.0.10-50`, `10.2.0.10-10.2.1.20` and host names,
+/// several of them separated by commas or spaces.
+pub async fn parse_targets(input: &str) -> io::Result<Vec<Ipv4Addr>> {
+    let mut targets = Vec::new();
+    let mut seen = HashSet::new();
+    for item in input.split(|c: char| c == ',' || c.is_whitespace()).filter(|s| !s.is_empty()) {
+        for ip in parse_item(item).await? {
+            if !seen.insert(ip) {
+                continue;
+            }
+            if targets.len() == MAX_TARGETS {
+                return Err(invalid(&format!("more than {} addresses", MAX_TARGETS)));
+            }
+            targets.push(ip);
+        }
+    }
+    if targets.is_empty() {
+        return Err(invalid("no address given"));
+    }
+    Ok(targets)
+}
+
+async fn parse_item(item: &str) -> io::Result<Vec<Ipv4Addr>> {
+    if let Ok(ip) = item.parse::<Ipv4Addr>() {
+        return Ok(vec![ip]);
+    }
+
+    if item.contains('/') {
+        let network: Ipv4Network = item.parse().map_err(|_| invalid(&format!("invalid network {}", item)))?;
+        // checked before `size()`, which overflows for a /0
+        if network.prefix() < MIN_PREFIX {
+            return Err(invalid(&format!("network {} is too large", item)));
+        }
+        // skip the network and broadcast address unless it is a point to point network
+        let hosts = network.iter()
+            .filter(|ip| network.prefix() >= 31 || (*ip != network.network() && *ip != network.broadcast()))
+            .collect();
+        return Ok(hosts);
+    }
+
+    if let Some((start, end)) = item.split_once('-') {
+        if let Ok(start) = start.parse::<Ipv4Addr>() {
+            // `10.2.0.10-50` only gives the last octet of the end
+            let end = match end.parse::<u8>() {
+                Ok(last) => {
+                    let o = start.octets();
+                    Ipv4Addr::new(o[0], o[1], o[2], last)
+                },
+                Err(_) => end.parse::<Ipv4Addr>().map_err(|_| invalid(&format!("invalid range {}", item)))?,
+            };
+            let (start, end) = (u32::from(start), u32::from(end));
+            if end < start {
+                return Err(invalid(&format!("invalid range {}", item)));
+            }
+            if (end - start) as usize >= MAX_TARGETS {
+                return Err(invalid(&format!("range {} is too large", item)));
+            }
+            return Ok((start..=end).map(Ipv4Addr::from).collect());
+        }
+    }
+
+    // host name
+    let ips: Vec<Ipv4Addr> = tokio::net::lookup_host((item, 0)).await
+        .map_err(|e| invalid(&format!("can't resolve {}: {}", item, e)))?
+        .filter_map(|addr| match addr.ip() {
+            std::net::IpAddr::V4(ip) => Some(ip),
+            _ => None,
+        })
+        .collect();
+    if ips.is_empty() {
+        return Err(invalid(&format!("{} has no ipv4 address", item)));
+    }
+    Ok(ips)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg.to_string())
+}
+
+/// probe every target through all discovery services at `PROBE_RATE`,
+/// then count the devices that answered from one of the targets.
+/// returns the number of probed addresses and answered devices.
+pub async fn sweep(targets: &[Ipv4Addr], disc_txs: &[tokio::sync::mpsc::Sender<String>], devices: &Arc<Mutex<Vec<RemoteTcpDevice>>>) -> io::Result<(usize, usize)> {
+    let start = Instant::now();
+    let mut timer = tokio::time::interval(Duration::from_micros(1_000_000 / PROBE_RATE));
+    for ip in targets {
+        timer.tick().await;
+        let ip = ip.to_string();
+        for tx in disc_txs {
+            tx.send(ip.clone()).await
+                .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "discovery service stopped"))?;
+        }
+    }
+    debug!("sweep sent {} probes, wait for answers", targets.len());
+    tokio::time::sleep(ANSWER_WAIT).await;
+
+    let targets: HashSet<Ipv4Addr> = targets.iter().cloned().collect();
+    let devices = devices.lock().unwrap();
+    let answered: HashSet<&str> = devices.iter()
+        .filter(|d| d.routes.iter().any(|r| r.last_seen.is_some_and(|seen| seen >= start) && match r.addr.ip() {
+            std::net::IpAddr::V4(ip) => targets.contains(&ip),
+            _ => false,
+        }))
+        .map(|d| d.device.id.as_str())
+        .collect();
+    info!("sweep of {} addresses, {} devices answered", targets.len(), answered.len());
+    Ok((targets.len(), answered.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(text: &str) -> Ipv4Addr {
+        text.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn addresses() {
+        assert_eq!(parse_targets("10.2.0.1").await.unwrap(), vec![ip("10.2.0.1")]);
+        assert_eq!(parse_targets(" 10.2.0.1, 10.2.0.2 10.2.0.1 ").await.unwrap(), vec![ip("10.2.0.1"), ip("10.2.0.2")]);
+        assert!(parse_targets("").await.is_err());
+        assert!(parse_targets(" , ").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn networks() {
+        let hosts = parse_targets("10.2.0.0/30").await.unwrap();
+        assert_eq!(hosts, vec![ip("10.2.0.1"), ip("10.2.0.2")]);
+        // point to point, both addresses are hosts
+        assert_eq!(parse_targets("10.2.0.0/31").await.unwrap(), vec![ip("10.2.0.0"), ip("10.2.0.1")]);
+        assert_eq!(parse_targets("10.2.0.5/32").await.unwrap(), vec![ip("10.2.0.5")]);
+        assert_eq!(parse_targets("10.2.0.0/24").await.unwrap().len(), 254);
+        assert!(parse_targets("10.2.0.0/33").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn large_networks() {
+        assert_eq!(parse_targets("10.2.0.0/16").await.unwrap().len(), 65534);
+        assert!(parse_targets("10.0.0.0/15").await.is_err());
+        assert!(parse_targets("0.0.0.0/0").await.is_err());
+        assert!(parse_targets("10.0.0.0/1").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn ranges() {
+        assert_eq!(parse_targets("10.2.0.10-12").await.unwrap(), vec![ip("10.2.0.10"), ip("10.2.0.11"), ip("10.2.0.12")]);
+        assert_eq!(parse_targets("10.2.0.255-10.2.1.0").await.unwrap(), vec![ip("10.2.0.255"), ip("10.2.1.0")]);
+        assert_eq!(parse_targets("10.2.0.10-10").await.unwrap(), vec![ip("10.2.0.10")]);
+        assert!(parse_targets("10.2.0.10-5").await.is_err());
+        assert!(parse_targets("10.2.0.10-256").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn limits() {
+        // exactly the limit
+        assert_eq!(parse_targets("10.2.0.0-10.2.255.255").await.unwrap().len(), MAX_TARGETS);
+        assert!(parse_targets("10.2.0.0-10.3.0.0").await.is_err());
+        // each item fits, together they don't
+        assert_eq!(parse_targets("10.2.0.0-10.2.255.254 10.3.0.0").await.unwrap().len(), MAX_TARGETS);
+        assert!(parse_targets("10.2.0.0-10.2.255.255 10.3.0.0").await.is_err());
+        // duplicates don't count
+        assert_eq!(parse_targets("10.2.0.0-10.2.255.255 10.2.0.1").await.unwrap().len(), MAX_TARGETS);
+    }
+}
@@ -0,0 +1,208 @@
+use serde::Serialize;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+use tokio::task::AbortHandle;
+
+use crate::device::RemoteTcpDevice;
+use super::command::Event;
+use super::subscriber::Subscribers;
+
+/// finished transfers kept in the list, older ones are dropped
+const KEEP_FINISHED: usize = 32;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferState {
+    /// an incoming transfer waiting for the user to accept it
+    Offered,
+    Running,
+    Done,
+    Failed(String),
+    Cancelled,
+    Declined,
+}
+
+impl TransferState {
+    /// offered or running, not ended yet
+    pub fn is_active(&self) -> bool {
+        matches!(self, TransferState::Offered | TransferState::Running)
+    }
+}
+
+/// one transfer as shown to front ends
+#[derive(Clone, Debug, Serialize)]
+pub struct TransferInfo {
+    pub id: u64,
+    /// sent to us rather than by us
+    pub incoming: bool,
+    /// id of the other device, empty for incoming transfers of devices not pinned
+    pub device: String,
+    /// name of the other device, the address for incoming transfers
+    pub name: String,
+    /// key fingerprint of the other device, if known
+    pub fingerprint: Option<String>,
+    /// paths of outgoing files, names of incoming ones
+    pub files: Vec<PathBuf>,
+    /// bytes transferred so far and the total size, the total is known once the meta data is sent
+    pub sent: u64,
+    pub total: u64,
+    pub state: TransferState,
+}
+
+struct Entry {
+    info: TransferInfo,
+    task: Option<AbortHandle>,
+    /// the answer to an offer
+    answer: Option<oneshot::Sender<bool>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    next_id: u64,
+    entries: Vec<Entry>,
+}
+
+/// the running and recently finished transfers, cheap to clone into transfer tasks
+#[derive(Clone, Default)]
+pub struct Transfers {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Transfers {
+    pub fn list(&self) -> Vec<TransferInfo> {
+        self.inner.lock().unwrap().entries.iter().map(|e| e.info.clone()).collect()
+    }
+
+    pub fn get(&self, id: u64) -> Option<TransferInfo> {
+        self.inner.lock().unwrap().entries.iter().find(|e| e.info.id == id).map(|e| e.info.clone())
+    }
+
+    /// add a running transfer to `device`
+    pub fn add(&self, device: &RemoteTcpDevice, files: Vec<PathBuf>) -> TransferInfo {
+        self.insert(TransferInfo {
+            id: 0,
+            incoming: false,
+            device: device.device.id.clone(),
+            name: device.device.name.clone(),
+            fingerprint: device.fingerprint.clone(),
+            files,
+            sent: 0,
+            total: 0,
+            state: TransferState::Running,
+        })
+    }
+
+    /// add a transfer offered to us by `from`, it waits for `offer` or `start`
+    pub fn add_incoming(&self, device: Option<String>, from: &str, fingerprint: &str, files: Vec<PathBuf>, total: u64) -> TransferInfo {
+        self.insert(TransferInfo {
+            id: 0,
+            incoming: true,
+            device: device.unwrap_or_default(),
+            name: from.to_string(),
+            fingerprint: Some(fingerprint.to_string()),
+            files,
+            sent: 0,
+            total,
+            state: TransferState::Offered,
+        })
+    }
+
+    fn insert(&self, mut info: TransferInfo) -> TransferInfo {
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id += 1;
+        info.id = inner.next_id;
+        inner.entries.push(Entry { info: info.clone(), task: None, answer: None });
+        info
+    }
+
+    /// wait for [`Transfers::answer`] on an offered transfer, true when accepted.
+    /// a cancelled offer is dropped, which reads as an error.
+    pub fn offer(&self, id: u64) -> oneshot::Receiver<bool> {
+        let (tx, rx) = oneshot::channel();
+        if let Some(entry) = self.inner.lock().unwrap().entries.iter_mut().find(|e| e.info.id == id) {
+            entry.answer = Some(tx);
+        }
+        rx
+    }
+
+    /// accept or decline an offered transfer, false when it isn't waiting for an answer
+    pub fn answer(&self, id: u64, accept: bool) -> bool {
+        let answer = self.inner.lock().unwrap().entries.iter_mut()
+            .find(|e| e.info.id == id && e.info.state == TransferState::Offered)
+            .and_then(|e| e.answer.take());
+        match answer {
+            Some(answer) => answer.send(accept).is_ok(),
+            None => false,
+        }
+    }
+
+    /// an offered transfer was accepted and its files are coming
+    pub fn start(&self, id: u64) {
+        if let Some(entry) = self.inner.lock().unwrap().entries.iter_mut().find(|e| e.info.id == id && e.info.state == TransferState::Offered) {
+            entry.info.state = TransferState::Running;
+        }
+    }
+
+    /// the task doing transfer `id`, aborted on cancel
+    pub fn set_task(&self, id: u64, task: AbortHandle) {
+        if let Some(entry) = self.inner.lock().unwrap().entries.iter_mut().find(|e| e.info.id == id) {
+            entry.task = Some(task);
+        }
+    }
+
+    pub fn progress(&self, id: u64, sent: u64, total: u64) {
+        if let Some(entry) = self.inner.lock().unwrap().entries.iter_mut().find(|e| e.info.id == id) {
+            entry.info.sent = sent;
+            entry.info.total = total;
+        }
+    }
+
+    /// a progress callback for transfer `id` that keeps the list up to date and
+    /// publishes a progress event whenever another percent is done
+    pub fn reporter(&self, id: u64, subscribers: Subscribers) -> impl FnMut(u64, u64) + Send + 'static {
+        let transfers = self.clone();
+        let mut last = None;
+        move |sent, total| {
+            transfers.progress(id, sent, total);
+            let percent = (sent * 100).checked_div(total).unwrap_or(100);
+            if last != Some(percent) {
+                last = Some(percent);
+                subscribers.publish(Event::TransferProgress { id, sent, total });
+            }
+        }
+    }
+
+    /// end an active transfer, returns it unless it already ended
+    pub fn finish(&self, id: u64, state: TransferState) -> Option<TransferInfo> {
+        let mut inner = self.inner.lock().unwrap();
+        let entry = inner.entries.iter_mut().find(|e| e.info.id == id && e.info.state.is_active())?;
+        entry.info.state = state;
+        entry.task = None;
+        entry.answer = None;
+        let info = entry.info.clone();
+
+        let finished = inner.entries.iter().filter(|e| !e.info.state.is_active()).count();
+        let mut drop = finished.saturating_sub(KEEP_FINISHED);
+        inner.entries.retain(|e| {
+            if drop > 0 && !e.info.state.is_active() {
+                drop -= 1;
+                return false;
+            }
+            true
+        });
+        Some(info)
+    }
+
+    /// stop an active transfer, returns it unless it already ended
+    pub fn cancel(&self, id: u64) -> Option<TransferInfo> {
+        let task = self.inner.lock().unwrap().entries.iter_mut()
+            .find(|e| e.info.id == id)
+            .and_then(|e| e.task.take());
+        let info = self.finish(id, TransferState::Cancelled)?;
+        if let Some(task) = task {
+            task.abort();
+        }
+        Some(info)
+    }
+}
@@ -1,7 +1,9 @@
 use serde::{Serialize, Deserialize};
+use log::info;
 use std::net::SocketAddr;
-use std::time::{SystemTime,UNIX_EPOCH};
+use std::time::{Duration,Instant,SystemTime,UNIX_EPOCH};
 
+use crate::share::ShareUri;
 use crate::utils;
 
 #[derive(Clone,Debug, Serialize, Deserialize)]
@@ -41,34 +43,312 @@ impl Device {
         }
     }
 
-    fn device_type(&self) -> &str {
-        &self.r#type
-    }
-
     fn share(&self) -> String {
-        format!("drop://{}/{}#{}",self.id,self.r#type,self.name)
+        ShareUri {
+            device: self.clone(),
+            addrs: Vec::new(),
+            fingerprint: None,
+        }.to_string()
     }
 }
 
+/// addresses kept per device, the most recently seen win
+const MAX_ROUTES: usize = 8;
+/// an address not announced for this long is dropped
+const ROUTE_EXPIRY: Duration = Duration::from_secs(600);
+
+/// one address a device can be reached on
+#[derive(Clone,Debug)]
+pub struct Route {
+    pub addr: SocketAddr,
+    /// when an announcement last arrived from it, `None` when only known from a cache or uri
+    pub last_seen: Option<Instant>,
+    /// how long connecting took on the last transfer
+    pub rtt: Option<Duration>,
+}
+
 #[derive(Clone,Debug)]
 pub struct RemoteTcpDevice {
+    /// address of the latest announcement, the relay for relayed devices
     pub addr: SocketAddr,
+    /// every direct address the device was seen on or is known by
+    pub routes: Vec<Route>,
     pub device: Device,
+    /// when the last discovery message from this device arrived
+    pub last_seen: Instant,
+    /// added by the user, kept when discovery stops seeing it
+    pub pinned: bool,
+    /// expected public key fingerprint, checked before sending
+    pub fingerprint: Option<String>,
+    /// false until the device answered discovery, e.g. when loaded from the known peers cache
+    pub reachable: bool,
+    /// only reachable through the relay, `addr` is the relay address
+    pub relayed: bool,
+    /// discovery protocol version, 0 when not announced yet
+    pub version: u32,
+    /// features the device announced
+    pub capabilities: Vec<String>,
+    /// false when the protocol versions can't talk to each other
+    pub compatible: bool,
+    /// joined rooms the device announced too
+    pub rooms: Vec<String>,
+    /// the device announced that it goes offline
+    pub left: bool,
 }
 
 impl RemoteTcpDevice {
-    pub fn new(ip: &str,port: u16,dev: Device) -> Self {
+    pub fn new(addr: SocketAddr,dev: Device) -> Self {
+        let now = Instant::now();
         Self{
-            addr: ip.parse().expect("ip is invalid"),
+            addr,
+            routes: vec![Route { addr, last_seen: Some(now), rtt: None }],
             device: dev,
+            last_seen: now,
+            pinned: false,
+            fingerprint: None,
+            reachable: true,
+            relayed: false,
+            version: 0,
+            capabilities: Vec::new(),
+            compatible: true,
+            rooms: Vec::new(),
+            left: false,
+        }
+    }
+
+    /// a device known by its addresses only, shown until it answers. `addrs` must not be empty.
+    pub fn unreachable(addrs: &[SocketAddr], dev: Device) -> Self {
+        let mut device = Self::new(addrs[0], dev);
+        device.routes = addrs.iter().map(|addr| Route { addr: *addr, last_seen: None, rtt: None }).collect();
+        device.reachable = false;
+        device
+    }
+
+    /// fold a newer announcement of the same device into this entry, keeping its recent
+    /// addresses. an announced port replaces the one known for that ip, e.g. after the
+    /// accepter of the device got another free port. the first fingerprint stays, once it
+    /// is known an announcement not signed with that key is ignored. a relayed announcement
+    /// doesn't replace a direct address.
+    pub fn merge(&mut self, newer: RemoteTcpDevice) {
+        if let Some(known) = &self.fingerprint {
+            if newer.fingerprint.as_ref() != Some(known) {
+                info!("ignore announcement of {} from {}: signed with key {}, known with {}", self.device.id, newer.addr, newer.fingerprint.as_deref().unwrap_or("none"), known);
+                return;
+            }
+        }
+        for route in newer.routes {
+            if route.last_seen.is_some() {
+                self.routes.retain(|r| r.addr.ip() != route.addr.ip() || r.addr == route.addr);
+            }
+            match self.routes.iter_mut().find(|r| r.addr == route.addr) {
+                Some(old) => old.last_seen = route.last_seen.or(old.last_seen),
+                None => self.routes.push(route),
+            }
+        }
+        // a device moving between networks or ports must not grow the list forever
+        self.routes.retain(|r| r.last_seen.is_none_or(|seen| seen.elapsed() < ROUTE_EXPIRY));
+        if self.routes.len() > MAX_ROUTES {
+            self.routes.sort_by_key(|r| std::cmp::Reverse(r.last_seen));
+            self.routes.truncate(MAX_ROUTES);
         }
+        if !newer.relayed || self.relayed || !self.reachable {
+            self.addr = newer.addr;
+            self.relayed = newer.relayed;
+        }
+        if self.fingerprint.is_none() {
+            self.fingerprint = newer.fingerprint;
+        }
+        self.device = newer.device;
+        self.last_seen = newer.last_seen;
+        self.reachable = true;
+        self.version = newer.version;
+        self.capabilities = newer.capabilities;
+        self.compatible = newer.compatible;
+        self.rooms = newer.rooms;
+    }
+
+    /// the direct addresses in the order to try them: the ones `local` says are in one of
+    /// our networks first, then the fastest to connect last time, then the most recently seen
+    pub fn routes_by_preference<F: Fn(&SocketAddr) -> bool>(&self, local: F) -> Vec<Route> {
+        let mut routes = self.routes.clone();
+        routes.sort_by_key(|r| (!local(&r.addr), r.rtt.is_none(), r.rtt, std::cmp::Reverse(r.last_seen)));
+        routes
     }
 
-    fn device_type(&self) -> &str {
-        &self.device.device_type()
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
     }
 
     pub fn share(&self) -> String {
         self.device.share()
     }
 }
+
+/// what front ends and the command line show of a device
+#[derive(Clone,Debug, Serialize)]
+pub struct DeviceInfo {
+    pub id: String,
+    pub name: String,
+    pub r#type: String,
+    pub addrs: Vec<String>,
+    pub fingerprint: Option<String>,
+    pub version: u32,
+    pub capabilities: Vec<String>,
+    pub compatible: bool,
+    pub rooms: Vec<String>,
+    pub pinned: bool,
+    pub reachable: bool,
+    pub relayed: bool,
+}
+
+impl From<&RemoteTcpDevice> for DeviceInfo {
+    fn from(d: &RemoteTcpDevice) -> Self {
+        Self {
+            id: d.device.id.clone(),
+            name: d.device.name.clone(),
+            r#type: d.device.r#type.clone(),
+            addrs: d.routes.iter().map(|r| r.addr.to_string()).collect(),
+            fingerprint: d.fingerprint.clone(),
+            version: d.version,
+            capabilities: d.capabilities.clone(),
+            compatible: d.compatible,
+            rooms: d.rooms.clone(),
+            pinned: d.pinned,
+            reachable: d.reachable,
+            relayed: d.relayed,
+        }
+    }
+}
+
+/// the device with id `target`, the one with the address `target` (ip or ip:port),
+/// or the only one called `target`
+pub fn pick<'a>(devices: &'a [RemoteTcpDevice], target: &str) -> std::io::Result<&'a RemoteTcpDevice> {
+    use std::io::{Error, ErrorKind};
+    if let Some(device) = devices.iter().find(|d| d.device.id == target) {
+        return Ok(device);
+    }
+    let by_addr = |d: &&RemoteTcpDevice| match (target.parse::<SocketAddr>(), target.parse::<std::net::IpAddr>()) {
+        (Ok(addr), _) => d.routes.iter().any(|r| r.addr == addr),
+        (_, Ok(ip)) => d.routes.iter().any(|r| r.addr.ip() == ip),
+        _ => false,
+    };
+    if let Some(device) = devices.iter().find(by_addr) {
+        return Ok(device);
+    }
+    let named: Vec<_> = devices.iter().filter(|d| d.device.name.eq_ignore_ascii_case(target)).collect();
+    match named.len() {
+        0 => Err(Error::new(ErrorKind::NotFound, format!("no device {} found", target))),
+        1 => Ok(named[0]),
+        n => Err(Error::new(ErrorKind::InvalidInput, format!("{} devices are called {}, use the id", n, target))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(addr: &str, fingerprint: Option<&str>) -> RemoteTcpDevice {
+        let mut device = RemoteTcpDevice::new(addr.parse().unwrap(), Device {
+            name: "box".to_string(),
+            r#type: "linux".to_string(),
+            id: "1-2".to_string(),
+        });
+        device.fingerprint = fingerprint.map(str::to_string);
+        device
+    }
+
+    fn addrs(device: &RemoteTcpDevice) -> Vec<String> {
+        device.routes.iter().map(|r| r.addr.to_string()).collect()
+    }
+
+    #[test]
+    fn merge_keeps_addresses() {
+        let mut known = device("10.0.0.2:52638", Some("aa"));
+        known.merge(device("192.168.1.2:52638", Some("aa")));
+        assert_eq!(addrs(&known), ["10.0.0.2:52638", "192.168.1.2:52638"]);
+        assert_eq!(known.addr, "192.168.1.2:52638".parse().unwrap());
+        // the same address again
+        known.merge(device("10.0.0.2:52638", Some("aa")));
+        assert_eq!(known.routes.len(), 2);
+    }
+
+    #[test]
+    fn merge_replaces_port() {
+        let mut known = RemoteTcpDevice::unreachable(&["10.0.0.2:52638".parse().unwrap()], device("10.0.0.2:1", None).device);
+        known.merge(device("10.0.0.2:40000", None));
+        assert_eq!(addrs(&known), ["10.0.0.2:40000"]);
+        assert!(known.reachable);
+    }
+
+    #[test]
+    fn merge_caps_routes() {
+        let mut known = device("10.0.0.1:52638", None);
+        for i in 2..=40 {
+            known.merge(device(&format!("10.0.0.{}:52638", i), None));
+        }
+        assert_eq!(known.routes.len(), MAX_ROUTES);
+        // the latest stays
+        assert!(addrs(&known).contains(&"10.0.0.40:52638".to_string()));
+    }
+
+    #[test]
+    fn merge_expires_routes() {
+        let mut known = device("10.0.0.1:52638", None);
+        match Instant::now().checked_sub(ROUTE_EXPIRY + Duration::from_secs(1)) {
+            Some(old) => known.routes[0].last_seen = Some(old),
+            // the clock started less than the expiry ago
+            None => return,
+        }
+        known.merge(device("10.0.0.2:52638", None));
+        assert_eq!(addrs(&known), ["10.0.0.2:52638"]);
+    }
+
+    #[test]
+    fn merge_keeps_first_fingerprint() {
+        let mut known = device("10.0.0.2:52638", None);
+        known.merge(device("10.0.0.2:52638", Some("aa")));
+        assert_eq!(known.fingerprint.as_deref(), Some("aa"));
+        // another key claiming the id changes nothing
+        let mut other = device("10.6.6.6:52638", Some("bb"));
+        other.device.name = "evil".to_string();
+        known.merge(other);
+        assert_eq!(known.fingerprint.as_deref(), Some("aa"));
+        assert_eq!(known.device.name, "box");
+        assert_eq!(addrs(&known), ["10.0.0.2:52638"]);
+    }
+
+    #[test]
+    fn merge_ignores_unsigned_for_signed_device() {
+        let mut known = device("10.0.0.2:52638", Some("aa"));
+        let mut unsigned = device("10.6.6.6:52638", None);
+        unsigned.device.name = "evil".to_string();
+        known.merge(unsigned);
+        assert_eq!(known.fingerprint.as_deref(), Some("aa"));
+        assert_eq!(known.device.name, "box");
+        assert_eq!(addrs(&known), ["10.0.0.2:52638"]);
+        assert_eq!(known.addr, "10.0.0.2:52638".parse().unwrap());
+    }
+
+    #[test]
+    fn merge_prefers_direct_address() {
+        let mut known = device("10.0.0.2:52638", None);
+        let mut relayed = device("172.16.0.1:52639", None);
+        relayed.relayed = true;
+        relayed.routes.clear();
+        known.merge(relayed);
+        assert!(!known.relayed);
+        assert_eq!(known.addr, "10.0.0.2:52638".parse().unwrap());
+    }
+
+    #[test]
+    fn routes_by_preference() {
+        let mut known = device("10.0.0.2:52638", None);
+        known.merge(device("192.168.1.2:52638", None));
+        known.merge(device("172.16.0.2:52638", None));
+        known.routes[2].rtt = Some(Duration::from_millis(5));
+        let local = |addr: &SocketAddr| addr.ip().to_string().starts_with("192.168.");
+        let order: Vec<String> = known.routes_by_preference(local).iter().map(|r| r.addr.to_string()).collect();
+        assert_eq!(order[0], "192.168.1.2:52638");
+        assert_eq!(order[1], "172.16.0.2:52638");
+    }
+}
@@ -0,0 +1,81 @@
+use std::fmt;
+use std::io;
+
+/// What went wrong talking to another device. Functions still returning
+/// `io::Result` take it with `?`, the variant maps to an `io::ErrorKind`.
+#[derive(Debug)]
+pub enum Error {
+    /// the network or the file system failed
+    Io(io::Error),
+    /// the other side sent something this version doesn't understand
+    Protocol(String),
+    /// a key couldn't be read or a cipher operation failed
+    Crypto(String),
+    /// the receiver declined the transfer
+    Rejected,
+    /// the transfer was stopped on this side
+    Cancelled,
+    /// the key of the other side isn't the one expected
+    VerificationFailed(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn protocol(message: impl ToString) -> Self {
+        Error::Protocol(message.to_string())
+    }
+
+    pub fn crypto(message: impl ToString) -> Self {
+        Error::Crypto(message.to_string())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Protocol(message) => write!(f, "protocol error: {}", message),
+            Error::Crypto(message) => write!(f, "crypto error: {}", message),
+            Error::Rejected => write!(f, "declined by the receiver"),
+            Error::Cancelled => write!(f, "cancelled"),
+            Error::VerificationFailed(message) => write!(f, "verification failed: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// a message that doesn't parse or serialize
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Protocol(e.to_string())
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        if let Error::Io(e) = e {
+            return e;
+        }
+        let kind = match &e {
+            Error::Io(_) | Error::Protocol(_) | Error::Crypto(_) => io::ErrorKind::InvalidData,
+            Error::Rejected | Error::VerificationFailed(_) => io::ErrorKind::PermissionDenied,
+            Error::Cancelled => io::ErrorKind::Interrupted,
+        };
+        io::Error::new(kind, e)
+    }
+}
@@ -14,9 +14,11 @@ impl FileMeta {
     pub fn new(file: &std::path::PathBuf) -> io::Result<Self> {
         let meta = file.metadata()?;
 
+        let name = file.file_name().and_then(|name| name.to_str())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} has no utf-8 file name", file.display())))?;
         let md5_val = file_md5(file)?;
         Ok(Self{
-            name: file.file_name().expect("is not file").to_str().expect("is not file").to_string(),
+            name: name.to_string(),
             size: meta.len(),
             verity: FileVerity{
                 r#type: "rsa".to_string(),
@@ -37,6 +39,13 @@ pub struct MetaList {
     pub files: Vec<FileMeta>,
 }
 
+impl MetaList {
+    /// bytes of all files, `None` when the sizes add up to more than fits a u64
+    pub fn total_size(&self) -> Option<u64> {
+        self.files.iter().try_fold(0u64, |total, f| total.checked_add(f.size))
+    }
+}
+
 pub fn file_md5(file: &std::path::PathBuf) -> io::Result<String> {
     let mut hasher = Md5::new();
     let mut f = std::fs::File::open(&file)?;
@@ -52,4 +61,25 @@ pub fn file_md5(file: &std::path::PathBuf) -> io::Result<String> {
         .map(|x| format!("{:02x}", x))
         .collect::<String>();
     Ok(md5_str)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(size: u64) -> FileMeta {
+        FileMeta {
+            name: "a".to_string(),
+            size,
+            verity: FileVerity { r#type: "md5".to_string(), data: String::new() },
+        }
+    }
+
+    #[test]
+    fn total_size() {
+        assert_eq!(MetaList { files: vec![] }.total_size(), Some(0));
+        assert_eq!(MetaList { files: vec![meta(1), meta(2)] }.total_size(), Some(3));
+        assert_eq!(MetaList { files: vec![meta(u64::MAX), meta(0)] }.total_size(), Some(u64::MAX));
+        // would wrap to 1 and pass any size limit
+        assert_eq!(MetaList { files: vec![meta(u64::MAX), meta(2)] }.total_size(), None);
+    }
+}
@@ -0,0 +1,78 @@
+use serde::{Serialize, Deserialize};
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use rsa::pkcs8::{DecodePrivateKey, EncodePrivateKey, EncodePublicKey};
+use sha2::{Sha256, Digest};
+use log::info;
+use std::io;
+
+use crate::device::Device;
+use crate::utils;
+
+const IDENTITY_FILE: &str = "identity.json";
+
+#[derive(Serialize, Deserialize)]
+struct IdentityFile {
+    id: String,
+    /// pkcs8 pem
+    key: String,
+}
+
+/// the device id and key pair, kept across restarts so peers can pin us
+pub struct Identity {
+    pub device: Device,
+    pub private_key: RsaPrivateKey,
+    pub public_key: RsaPublicKey,
+}
+
+impl Identity {
+    /// load the identity from the data directory, or create and save a new one
+    pub fn load_or_create() -> io::Result<Self> {
+        let path = utils::data_dir()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no data directory"))?
+            .join(IDENTITY_FILE);
+        if path.exists() {
+            let data = std::fs::read(&path)?;
+            let file: IdentityFile = serde_json::from_slice(&data)?;
+            let private_key = RsaPrivateKey::from_pkcs8_pem(&file.key)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?;
+            return Ok(Self::from_parts(file.id, private_key));
+        }
+
+        let identity = Self::generate();
+        let file = IdentityFile {
+            id: identity.device.id.clone(),
+            key: identity.private_key.to_pkcs8_pem(base64ct::LineEnding::LF)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?
+                .to_string(),
+        };
+        utils::write_private(&path, &serde_json::to_vec_pretty(&file)?)?;
+        info!("created identity {} in {}", identity.device.id, path.display());
+        Ok(identity)
+    }
+
+    /// a new identity that is not saved
+    pub fn generate() -> Self {
+        let mut rng = rand::thread_rng();
+        let private_key = RsaPrivateKey::new(&mut rng, 2048).expect("failed to generate a key");
+        Self::from_parts(Device::default().id, private_key)
+    }
+
+    fn from_parts(id: String, private_key: RsaPrivateKey) -> Self {
+        let public_key = RsaPublicKey::from(&private_key);
+        let mut device = Device::default();
+        device.id = id;
+        Self {
+            device,
+            private_key,
+            public_key,
+        }
+    }
+}
+
+/// hex sha256 of the DER encoded public key
+pub fn fingerprint(key: &RsaPublicKey) -> String {
+    let der = key.to_public_key_der().expect("can't covert to der");
+    Sha256::digest(der.as_bytes()).iter()
+        .map(|x| format!("{:02x}", x))
+        .collect::<String>()
+}
@@ -0,0 +1,90 @@
+use pnet::ipnetwork::{IpNetwork, Ipv4Network};
+
+use crate::config::InterfaceConfig;
+use crate::utils;
+
+/// one interface address and whether the configuration selects it
+#[derive(Clone,Debug)]
+pub struct Selection {
+    pub name: String,
+    pub network: Option<Ipv4Network>,
+    pub selected: bool,
+    pub reason: String,
+}
+
+/// check every interface address against the rules, in the order they are listed
+pub fn select(rules: &InterfaceConfig) -> Vec<Selection> {
+    let include_cidr: Vec<Ipv4Network> = rules.include_cidr.iter().filter_map(|c| c.parse().ok()).collect();
+    let exclude_cidr: Vec<Ipv4Network> = rules.exclude_cidr.iter().filter_map(|c| c.parse().ok()).collect();
+
+    let mut selections = Vec::new();
+    for interface in pnet::datalink::interfaces() {
+        let name = interface.name.clone();
+        let reject = |network: Option<Ipv4Network>, reason: String| Selection {
+            name: name.clone(),
+            network,
+            selected: false,
+            reason,
+        };
+
+        if !interface.is_up() {
+            selections.push(reject(None, "interface is down".to_string()));
+            continue;
+        }
+        if interface.is_loopback() && !rules.loopback {
+            selections.push(reject(None, "loopback interface".to_string()));
+            continue;
+        }
+        if let Some(pattern) = rules.exclude.iter().find(|p| utils::glob_match(p, &name)) {
+            selections.push(reject(None, format!("name matches exclude \"{}\"", pattern)));
+            continue;
+        }
+        if !rules.include.is_empty() && !rules.include.iter().any(|p| utils::glob_match(p, &name)) {
+            selections.push(reject(None, "name matches no include pattern".to_string()));
+            continue;
+        }
+
+        let networks: Vec<Ipv4Network> = interface.ips.iter().filter_map(|ip| match ip {
+            IpNetwork::V4(network) => Some(*network),
+            _ => None,
+        }).collect();
+        if networks.is_empty() {
+            selections.push(reject(None, "no ipv4 address".to_string()));
+            continue;
+        }
+
+        for network in networks {
+            let ip = network.ip();
+            if let Some(cidr) = exclude_cidr.iter().find(|c| c.contains(ip)) {
+                selections.push(reject(Some(network), format!("address in excluded network {}", cidr)));
+            } else if !include_cidr.is_empty() && !include_cidr.iter().any(|c| c.contains(ip)) {
+                selections.push(reject(Some(network), "address in no included network".to_string()));
+            } else {
+                selections.push(Selection {
+                    name: name.clone(),
+                    network: Some(network),
+                    selected: true,
+                    reason: "selected".to_string(),
+                });
+            }
+        }
+    }
+    selections
+}
+
+/// the selected interface addresses
+pub fn selected_networks(rules: &InterfaceConfig) -> Vec<(String, Ipv4Network)> {
+    select(rules).into_iter()
+        .filter(|s| s.selected)
+        .filter_map(|s| Some((s.name, s.network?)))
+        .collect()
+}
+
+/// print the selection result as a table
+pub fn print_selection(rules: &InterfaceConfig) {
+    println!("{:<16} {:<20} {:<4} reason", "interface", "address", "use");
+    for s in select(rules) {
+        let address = s.network.map(|n| n.to_string()).unwrap_or_else(|| "-".to_string());
+        println!("{:<16} {:<20} {:<4} {}", s.name, address, if s.selected { "yes" } else { "no" }, s.reason);
+    }
+}
@@ -4,4 +4,11 @@ use serde::{Serialize, Deserialize};
 pub struct KeyObject {
     pub r#type: String,
     pub data: String,
-}
\ No newline at end of file
+    /// set by the sender when a session key follows and the transfer is encrypted,
+    /// only sent to receivers announcing the encryption capability
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<String>,
+}
+
+/// the only session cipher so far
+pub const ENCRYPTION_CHACHA20POLY1305: &str = "chacha20poly1305";
\ No newline at end of file
@@ -0,0 +1,142 @@
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::io;
+use log::info;
+
+use crate::device::{Device, RemoteTcpDevice};
+use crate::utils;
+
+const KNOWN_PEERS_FILE: &str = "known_peers.json";
+/// peers not seen for the longest time are forgotten beyond this
+const MAX_PEERS: usize = 64;
+/// addresses kept per peer, most recent first
+const MAX_ADDRS: usize = 4;
+
+#[derive(Clone,Debug, Serialize, Deserialize)]
+pub struct KnownPeer {
+    pub device: Device,
+    pub addrs: Vec<SocketAddr>,
+    #[serde(default)]
+    pub fingerprint: Option<String>,
+    #[serde(default)]
+    pub pinned: bool,
+    /// unix time in seconds
+    pub last_seen: u64,
+}
+
+impl KnownPeer {
+    /// device list entry shown before the peer answers again
+    pub fn to_device(&self) -> Option<RemoteTcpDevice> {
+        if self.addrs.is_empty() {
+            return None;
+        }
+        let mut device = RemoteTcpDevice::unreachable(&self.addrs, self.device.clone());
+        device.pinned = self.pinned;
+        device.fingerprint = self.fingerprint.clone();
+        Some(device)
+    }
+}
+
+/// peers seen before, kept on disk so they show up right after start
+pub struct KnownPeers {
+    path: Option<PathBuf>,
+    peers: Vec<KnownPeer>,
+    dirty: bool,
+}
+
+impl KnownPeers {
+    /// load the cache from the data directory, a missing or broken file gives an empty cache
+    pub fn load() -> Self {
+        let path = utils::data_dir().map(|dir| dir.join(KNOWN_PEERS_FILE));
+        let peers = match &path {
+            Some(path) if path.exists() => match std::fs::read(path).map(|data| serde_json::from_slice(&data)) {
+                Ok(Ok(peers)) => peers,
+                Ok(Err(e)) => {
+                    info!("ignore broken {}: {}", path.display(), e);
+                    Vec::new()
+                },
+                Err(e) => {
+                    info!("can't read {}: {}", path.display(), e);
+                    Vec::new()
+                },
+            },
+            _ => Vec::new(),
+        };
+        Self {
+            path,
+            peers,
+            dirty: false,
+        }
+    }
+
+    pub fn peers(&self) -> &[KnownPeer] {
+        &self.peers
+    }
+
+    /// fingerprints of the pinned peers by device id
+    pub fn pins(&self) -> HashMap<String, String> {
+        self.peers.iter()
+            .filter(|p| p.pinned)
+            .filter_map(|p| Some((p.device.id.clone(), p.fingerprint.clone()?)))
+            .collect()
+    }
+
+    /// record a device that was just seen or added
+    pub fn update(&mut self, dev: &RemoteTcpDevice) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        match self.peers.iter_mut().find(|p| p.device.id == dev.device.id) {
+            Some(peer) => {
+                if peer.device.name != dev.device.name || peer.device.r#type != dev.device.r#type {
+                    peer.device = dev.device.clone();
+                    self.dirty = true;
+                }
+                if peer.addrs.first() != Some(&dev.addr) {
+                    peer.addrs.retain(|addr| *addr != dev.addr);
+                    peer.addrs.insert(0, dev.addr);
+                    peer.addrs.truncate(MAX_ADDRS);
+                    self.dirty = true;
+                }
+                if dev.pinned && (!peer.pinned || peer.fingerprint != dev.fingerprint) {
+                    peer.pinned = true;
+                    peer.fingerprint = dev.fingerprint.clone();
+                    self.dirty = true;
+                }
+                // only worth a write together with other changes
+                peer.last_seen = now;
+            },
+            None => {
+                self.peers.push(KnownPeer {
+                    device: dev.device.clone(),
+                    addrs: vec![dev.addr],
+                    fingerprint: dev.fingerprint.clone(),
+                    pinned: dev.pinned,
+                    last_seen: now,
+                });
+                if self.peers.len() > MAX_PEERS {
+                    // forget the peer seen longest ago, pinned ones last
+                    if let Some(oldest) = self.peers.iter().enumerate()
+                        .min_by_key(|(_, p)| (p.pinned, p.last_seen))
+                        .map(|(i, _)| i) {
+                        self.peers.remove(oldest);
+                    }
+                }
+                self.dirty = true;
+            },
+        }
+    }
+
+    /// write the cache if anything important changed since the last save
+    pub fn save(&mut self) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        if let Some(path) = &self.path {
+            utils::write_private(path, &serde_json::to_vec_pretty(&self.peers)?)?;
+        }
+        self.dirty = false;
+        Ok(())
+    }
+}
@@ -0,0 +1,45 @@
+//! Local network file transfer: discover devices on the lan, through a relay or by
+//! address, and send files to them.
+//!
+//! [`Controller`] runs everything an instance needs: discovery and an accepter on every
+//! selected interface, the relay connection and the device list. The building blocks
+//! can be used on their own as well:
+//!
+//! - [`Discovery`] sends and answers [`DiscoveryReq`]s on one interface address
+//! - [`Accepter`] receives files, [`ClientConnector`] sends them
+//! - [`key_object`], [`file_meta`], [`relay_proto`] and [`share`] hold the protocol types
+//!
+//! All network calls are async and need a tokio runtime. They fail with an [`Error`]
+//! that converts into `std::io::Error`.
+//!
+//! ```no_run
+//! use simp_drop::ClientConnector;
+//!
+//! # async fn send(key: rsa::RsaPublicKey) -> simp_drop::Result<()> {
+//! let mut conn = ClientConnector::connect("192.168.1.20:52638").await?;
+//! let _receiver_key = conn.send_public_key(&key, false).await?;
+//! conn.send_files(&vec!["report.pdf".into()]).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod config;
+pub mod controller;
+pub mod device;
+pub mod error;
+pub mod file_meta;
+pub mod identity;
+pub mod interfaces;
+pub mod key_object;
+pub mod known_peers;
+pub mod relay_proto;
+pub mod settings;
+pub mod share;
+pub mod signals;
+mod utils;
+
+pub use controller::Controller;
+pub use controller::accepter::Accepter;
+pub use controller::connector::ClientConnector;
+pub use controller::discoverer::{Discovery, DiscoveryReq};
+pub use error::{Error, Result};
@@ -1,18 +1,33 @@
-mod controller;
-mod device;
-mod utils;
-mod file_meta;
-mod key_object;
 mod ui;
 mod components;
 
 use log::debug;
+use simp_drop::{config, interfaces, share};
 
 fn main() -> std::io::Result<()> {
     //env_logger::init();
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("debug")).init();
     debug!("start simple rust drop");
     
-    ui::start()?;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let config_path = match args.iter().position(|arg| arg == "--config") {
+        Some(i) => Some(args.get(i + 1)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "--config needs a file"))?),
+        None => None,
+    };
+    let config = match config::Config::load_at(config_path.map(std::path::Path::new)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("simp_drop: {}", e);
+            std::process::exit(1);
+        },
+    };
+    if args.iter().any(|arg| arg == "--list-interfaces") {
+        interfaces::print_selection(&config.interfaces);
+        return Ok(());
+    }
+    // drop:// uris given on the command line are added as pinned devices
+    let uris: Vec<String> = args.into_iter().filter(|arg| share::ShareUri::is_share_uri(arg)).collect();
+    ui::start(config, uris)?;
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,56 @@
+use serde::{Serialize, Deserialize};
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncReadExt, AsyncWriteExt};
+
+pub const RELAY_PORT: u16 = 52639u16;
+/// longest message line accepted
+const MAX_LINE: usize = 64 * 1024;
+
+/// messages between instances and the relay server, one json object per line
+#[derive(Clone,Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum RelayMessage {
+    /// client -> relay, first message of the control connection
+    Register { id: String },
+    /// relay -> client
+    Registered,
+    /// a discovery request, forwarded to `to` or every other client.
+    /// the relay sets `from` to the id the sender registered with.
+    Discovery {
+        #[serde(default)]
+        from: Option<String>,
+        #[serde(default)]
+        to: Option<String>,
+        data: serde_json::Value,
+    },
+    /// sender -> relay, first message of a data connection
+    Connect { from: String, to: String, session: String },
+    /// relay -> receiver on its control connection, open a data connection for `session`
+    Incoming { from: String, session: String },
+    /// receiver -> relay, first message of a data connection
+    Accept { session: String },
+    /// relay -> both ends of a data connection, the peer's bytes follow
+    Connected,
+    Error { message: String },
+}
+
+/// read one message, byte by byte so nothing after the line is consumed
+pub async fn read_message<T: AsyncRead + Unpin>(stream: &mut T) -> io::Result<RelayMessage> {
+    let mut line = Vec::new();
+    loop {
+        let b = stream.read_u8().await?;
+        if b == b'\n' {
+            break;
+        }
+        if line.len() >= MAX_LINE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "relay message is too long"));
+        }
+        line.push(b);
+    }
+    Ok(serde_json::from_slice(&line)?)
+}
+
+pub async fn write_message<T: AsyncWrite + Unpin>(stream: &mut T, msg: &RelayMessage) -> io::Result<()> {
+    let mut data = serde_json::to_vec(msg)?;
+    data.push(b'\n');
+    stream.write_all(&data).await
+}
@@ -0,0 +1,92 @@
+use serde::{Serialize, Deserialize};
+use std::path::PathBuf;
+use std::io;
+use log::info;
+
+use crate::utils;
+
+const SETTINGS_FILE: &str = "settings.json";
+
+/// who this device answers and announces itself to
+#[derive(Clone,Copy,Debug,Default,PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    /// announce and answer every discovery request
+    #[default]
+    Everyone,
+    /// only answer pinned devices, no multicast, broadcast or relay announcements
+    Known,
+    /// no announcements and no answers, still receives on its address
+    Hidden,
+}
+
+impl Visibility {
+    pub const ALL: [Visibility; 3] = [Visibility::Everyone, Visibility::Known, Visibility::Hidden];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Visibility::Everyone => "everyone",
+            Visibility::Known => "known devices",
+            Visibility::Hidden => "hidden",
+        }
+    }
+
+    /// send announcements everybody on the network can see
+    pub fn announces(&self) -> bool {
+        *self == Visibility::Everyone
+    }
+
+    /// send requests to addresses the user asked for
+    pub fn probes(&self) -> bool {
+        *self != Visibility::Hidden
+    }
+
+    /// answer the request of a device
+    pub fn answers(&self, pinned: bool) -> bool {
+        match self {
+            Visibility::Everyone => true,
+            Visibility::Known => pinned,
+            Visibility::Hidden => false,
+        }
+    }
+}
+
+/// choices made in the ui, kept in the data directory
+#[derive(Clone,Debug,Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub visibility: Visibility,
+    /// joined rooms, only devices in one of them are shown
+    pub rooms: Vec<String>,
+}
+
+impl Settings {
+    fn path() -> Option<PathBuf> {
+        utils::data_dir().map(|dir| dir.join(SETTINGS_FILE))
+    }
+
+    /// a missing or broken file gives the default settings
+    pub fn load() -> Self {
+        match Self::path() {
+            Some(path) if path.exists() => match std::fs::read(&path).map(|data| serde_json::from_slice(&data)) {
+                Ok(Ok(settings)) => settings,
+                Ok(Err(e)) => {
+                    info!("ignore broken {}: {}", path.display(), e);
+                    Self::default()
+                },
+                Err(e) => {
+                    info!("can't read {}: {}", path.display(), e);
+                    Self::default()
+                },
+            },
+            _ => Self::default(),
+        }
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        match Self::path() {
+            Some(path) => utils::write_private(&path, &serde_json::to_vec_pretty(self)?),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::fmt;
+
+use crate::device::Device;
+use crate::utils;
+
+const SCHEME: &str = "drop://";
+
+/// `drop://<id>/<type>?addr=<ip:port>&fp=<fingerprint>#<name>`
+///
+/// `addr` may repeat, an address without port uses the `port` parameter
+/// or the receive port of this device.
+#[derive(Clone,Debug)]
+pub struct ShareUri {
+    pub device: Device,
+    pub addrs: Vec<SocketAddr>,
+    pub fingerprint: Option<String>,
+}
+
+impl ShareUri {
+    pub fn is_share_uri(text: &str) -> bool {
+        // not a char boundary for e.g. "ab例えば", which is simply no uri
+        text.get(..SCHEME.len()).is_some_and(|prefix| prefix.eq_ignore_ascii_case(SCHEME))
+    }
+
+    pub fn parse(text: &str, default_port: u16) -> io::Result<Self> {
+        let text = text.trim();
+        if !Self::is_share_uri(text) {
+            return Err(invalid("uri must start with drop://"));
+        }
+        let rest = &text[SCHEME.len()..];
+        let (rest, name) = match rest.split_once('#') {
+            Some((rest, name)) => (rest, utils::percent_decode(name).ok_or_else(|| invalid("invalid device name"))?),
+            None => (rest, String::new()),
+        };
+        let (path, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let (id, r#type) = path.split_once('/').unwrap_or((path, ""));
+        let id = utils::percent_decode(id).ok_or_else(|| invalid("invalid device id"))?;
+        if id.is_empty() {
+            return Err(invalid("device id is missing"));
+        }
+
+        let mut port = default_port;
+        let mut ips = Vec::new();
+        let mut addrs = Vec::new();
+        let mut fingerprint = None;
+        for pair in query.split('&').filter(|p| !p.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let value = utils::percent_decode(value).ok_or_else(|| invalid("invalid query value"))?;
+            match key {
+                "addr" => {
+                    if let Ok(addr) = value.parse::<SocketAddr>() {
+                        addrs.push(addr);
+                    } else {
+                        ips.push(value.parse::<IpAddr>().map_err(|_| invalid(&format!("invalid address {}", value)))?);
+                    }
+                },
+                "port" => port = value.parse().map_err(|_| invalid(&format!("invalid port {}", value)))?,
+                "fp" => fingerprint = Some(value.to_ascii_lowercase()),
+                _ => {},
+            }
+        }
+        addrs.extend(ips.into_iter().map(|ip| SocketAddr::new(ip, port)));
+
+        Ok(Self {
+            device: Device {
+                name: if name.is_empty() { id.clone() } else { name },
+                r#type: utils::percent_decode(r#type).unwrap_or_default(),
+                id,
+            },
+            addrs,
+            fingerprint,
+        })
+    }
+}
+
+impl fmt::Display for ShareUri {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}/{}", SCHEME, utils::percent_encode(&self.device.id), utils::percent_encode(&self.device.r#type))?;
+        let mut params: Vec<String> = self.addrs.iter()
+            .map(|addr| format!("addr={}", addr))
+            .collect();
+        if let Some(fp) = &self.fingerprint {
+            params.push(format!("fp={}", fp));
+        }
+        if !params.is_empty() {
+            write!(f, "?{}", params.join("&"))?;
+        }
+        write!(f, "#{}", utils::percent_encode(&self.device.name))
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_share_uri() {
+        assert!(ShareUri::is_share_uri("drop://abc"));
+        assert!(ShareUri::is_share_uri("DROP://abc"));
+        assert!(!ShareUri::is_share_uri("drop:/"));
+        assert!(!ShareUri::is_share_uri("http://abc"));
+        // byte 7 is inside a multi-byte character
+        assert!(!ShareUri::is_share_uri("ab例えば"));
+        assert!(!ShareUri::is_share_uri("例えば例えば"));
+    }
+
+    #[test]
+    fn parse() {
+        let uri = ShareUri::parse("drop://123-45/linux?addr=10.0.0.2:4000&addr=10.0.0.3&port=5000&fp=ABCD#my%20box", 52638).unwrap();
+        assert_eq!(uri.device.id, "123-45");
+        assert_eq!(uri.device.r#type, "linux");
+        assert_eq!(uri.device.name, "my box");
+        assert_eq!(uri.fingerprint.as_deref(), Some("abcd"));
+        assert_eq!(uri.addrs, vec!["10.0.0.2:4000".parse().unwrap(), "10.0.0.3:5000".parse().unwrap()]);
+    }
+
+    #[test]
+    fn parse_defaults() {
+        let uri = ShareUri::parse("  drop://abc?addr=10.0.0.3  ", 52638).unwrap();
+        assert_eq!(uri.device.name, "abc");
+        assert_eq!(uri.device.r#type, "");
+        assert_eq!(uri.fingerprint, None);
+        assert_eq!(uri.addrs, vec!["10.0.0.3:52638".parse().unwrap()]);
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!(ShareUri::parse("http://abc", 1).is_err());
+        assert!(ShareUri::parse("drop:///linux", 1).is_err());
+        assert!(ShareUri::parse("drop://abc?addr=host", 1).is_err());
+        assert!(ShareUri::parse("drop://abc?port=70000", 1).is_err());
+        assert!(ShareUri::parse("drop://abc#%zz", 1).is_err());
+        assert!(ShareUri::parse("ab例えば", 1).is_err());
+    }
+
+    #[test]
+    fn display_round_trip() {
+        let uri = ShareUri {
+            device: Device { name: "laptop of ana/b".to_string(), r#type: "linux".to_string(), id: "1-2".to_string() },
+            addrs: vec!["192.168.1.2:52638".parse().unwrap(), "10.0.0.2:1000".parse().unwrap()],
+            fingerprint: Some("ab12".to_string()),
+        };
+        let text = uri.to_string();
+        let parsed = ShareUri::parse(&text, 1).unwrap();
+        assert_eq!(parsed.device.name, uri.device.name);
+        assert_eq!(parsed.device.r#type, uri.device.r#type);
+        assert_eq!(parsed.device.id, uri.device.id);
+        assert_eq!(parsed.addrs, uri.addrs);
+        assert_eq!(parsed.fingerprint, uri.fingerprint);
+    }
+}
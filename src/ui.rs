@@ -1,84 +1,320 @@
 mod device;
 
-use crate::device::RemoteTcpDevice;
-use crate::controller;
+use crate::components::qr_code::QrCodeView;
+use simp_drop::device::RemoteTcpDevice;
+use simp_drop::config::Config;
+use simp_drop::controller;
+use simp_drop::controller::command::{Command, Event};
+use simp_drop::controller::subscriber::Subscriber;
+use simp_drop::controller::transfers::TransferInfo;
+use simp_drop::settings::{Settings, Visibility};
+use simp_drop::signals::Signals;
 use std::sync::{Arc,Mutex};
-use log::{debug,info};
+use log::info;
 use eframe::egui;
 
+/// how long closing the window waits for the controller, a bit more than its transfer grace
+const BACKEND_STOP_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
 struct MyApp {
+    config: Config,
     discovery_ip: String,
     devices:  Arc<Mutex<Vec<RemoteTcpDevice>>>,
+    share: Arc<Mutex<String>>,
+    settings: Settings,
+    /// room name typed in the join box
+    room: String,
+    show_share: bool,
+    /// qr code of the share uri it was made from
+    qr: Option<(String, qrcode::QrCode)>,
     backend_run: bool,
-    cts: Option<tokio::sync::mpsc::Sender<String>>
+    cts: Option<tokio::sync::mpsc::Sender<Command>>,
+    crx: Option<tokio::sync::broadcast::Receiver<Event>>,
+    /// thread running the controller, joined on exit. true when it stopped on a signal
+    backend: Option<std::thread::JoinHandle<bool>>,
+    /// last message from the backend
+    status: String,
+    /// incoming transfers waiting for accept or decline
+    offers: Vec<TransferInfo>,
+    /// drop:// uris to add once the backend runs
+    uris: Vec<String>,
 }
 
-impl Default for MyApp {
-    fn default() -> Self {
+impl MyApp {
+    fn new(config: Config, uris: Vec<String>) -> Self {
+        let settings = Settings::load();
         Self {
+            config,
+            uris,
             discovery_ip: "".to_string(),
             devices: Arc::new(Mutex::new(Vec::<RemoteTcpDevice>::new())),
+            share: Arc::new(Mutex::new(String::new())),
+            room: String::new(),
+            settings,
+            show_share: false,
+            qr: None,
             backend_run: false,
             cts: None,
+            crx: None,
+            backend: None,
+            status: "".to_string(),
+            offers: Vec::new(),
+        }
+    }
+
+    /// hand changed settings to the controller, it applies and saves them
+    fn settings_changed(&self) {
+        if let Some(cts) = &self.cts {
+            if let Err(e) = cts.try_send(Command::SetSettings(self.settings.clone())) {
+                info!("change settings failed: {}", e);
+            }
+        }
+    }
+
+    fn handle_event(&mut self, event: Event) {
+        match &event {
+            Event::IncomingOffer { transfer } => self.offers.push(transfer.clone()),
+            Event::TransferStarted { transfer } | Event::TransferFinished { transfer } => {
+                self.offers.retain(|o| o.id != transfer.id);
+            },
+            _ => {},
+        }
+        match event {
+            // too many for the status line
+            Event::TransferProgress { .. } | Event::DeviceFound { .. } | Event::DeviceLost { .. } => {},
+            event => self.status = event.to_string(),
+        }
+    }
+
+    /// incoming transfers the user has to answer
+    fn offers_ui(&mut self, ui: &mut egui::Ui) {
+        let mut answer = None;
+        for offer in &self.offers {
+            ui.horizontal(|ui| {
+                ui.label(format!("{} wants to send {} files, {:.1} MiB", offer.name, offer.files.len(), offer.total as f64 / (1024.0 * 1024.0)))
+                    .on_hover_text(offer.files.iter().map(|f| f.display().to_string()).collect::<Vec<_>>().join("\n"));
+                if ui.button("accept").clicked() {
+                    answer = Some(Command::Accept(offer.id));
+                }
+                if ui.button("decline").clicked() {
+                    answer = Some(Command::Decline(offer.id));
+                }
+            });
+        }
+        if let (Some(answer), Some(cts)) = (answer, &self.cts) {
+            if let Err(e) = cts.try_send(answer) {
+                info!("answer offer failed: {}", e);
+            }
         }
     }
+
+    fn share_window(&mut self, ctx: &egui::Context) {
+        if !self.show_share {
+            return;
+        }
+        let uri = self.share.lock().unwrap().clone();
+        if self.qr.as_ref().map(|(text, _)| text != &uri).unwrap_or(true) {
+            self.qr = qrcode::QrCode::new(&uri).ok().map(|code| (uri.clone(), code));
+        }
+        let qr = &self.qr;
+        egui::Window::new("share")
+            .open(&mut self.show_share)
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                match qr {
+                    Some((_, code)) => {
+                        ui.add(QrCodeView::new(code).module_size(4.0));
+                    },
+                    None => {
+                        ui.label("no share uri yet");
+                    },
+                }
+                let mut text = uri.as_str();
+                ui.add(egui::TextEdit::multiline(&mut text).desired_width(280.0));
+            });
+    }
+
+    /// join and leave rooms, changes are saved right away
+    fn rooms_ui(&mut self, ui: &mut egui::Ui) {
+        let mut rooms = self.settings.rooms.clone();
+        ui.horizontal(|ui| {
+            ui.add(egui::TextEdit::singleline(&mut self.room).hint_text("room").desired_width(160.0));
+            let room = self.room.trim().to_string();
+            if ui.button("join").on_hover_text("only show devices in the joined rooms").clicked()
+                && !room.is_empty() && !rooms.contains(&room) {
+                rooms.push(room);
+                self.room.clear();
+            }
+            let mut leave = None;
+            for (i, room) in rooms.iter().enumerate() {
+                if ui.button(format!("{} \u{2715}", room)).on_hover_text("leave the room").clicked() {
+                    leave = Some(i);
+                }
+            }
+            if let Some(i) = leave {
+                rooms.remove(i);
+            }
+        });
+        if rooms != self.settings.rooms {
+            self.settings.rooms = rooms;
+            self.settings_changed();
+        }
+    }
+}
+
+/// device cards, two per row
+fn device_grid(ui: &mut egui::Ui, devices: &[&RemoteTcpDevice]) {
+    for row in devices.chunks(2) {
+        ui.with_layout(egui::Layout::left_to_right(egui::Align::Min),|ui|{
+            for dev in row {
+                device::Device{
+                    name: dev.device.name.clone(),
+                    ip: match dev.routes.len() {
+                        0 | 1 => dev.addr.ip().to_string(),
+                        n => format!("{} +{}", dev.addr.ip(), n - 1),
+                    },
+                    r#type: dev.device.r#type.clone(),
+                    id: dev.device.id.clone(),
+                    pinned: dev.pinned,
+                    reachable: dev.reachable,
+                    compatible: dev.compatible,
+                }.ui(ui);
+            }
+        });
+    }
 }
 
 impl eframe::App for MyApp {
-    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+    fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
+        // the backend stopped on SIGINT or SIGTERM and already shut down
+        if self.backend.as_ref().is_some_and(|b| b.is_finished()) {
+            if let Some(Ok(true)) = self.backend.take().map(|b| b.join()) {
+                frame.close();
+            }
+        }
         egui::CentralPanel::default().show(ctx, |ui| {
             if !self.backend_run {
                 let devices = self.devices.clone();
-                // start backend
-                let cts = start_backend(ctx.clone(),devices).expect("backend run failed");
-                self.cts = Some(cts);
+                let share = self.share.clone();
+                // start backend, once: without it the window only shows why
                 self.backend_run = true;
+                match start_backend(ctx.clone(),self.config.clone(),devices,share) {
+                    Ok((cts,crx,backend)) => {
+                        for uri in self.uris.drain(..) {
+                            if let Err(e) = cts.try_send(Command::AddPeer(uri)) {
+                                info!("add uri failed: {}", e);
+                            }
+                        }
+                        self.cts = Some(cts);
+                        self.crx = Some(crx);
+                        self.backend = Some(backend);
+                    },
+                    Err(e) => {
+                        info!("start backend failed: {}", e);
+                        self.status = format!("start backend failed: {}", e);
+                    },
+                }
+            }
+            while let Some(crx) = self.crx.as_mut() {
+                match crx.try_recv() {
+                    Ok(event) => self.handle_event(event),
+                    Err(tokio::sync::broadcast::error::TryRecvError::Lagged(_)) => continue,
+                    Err(_) => break,
+                }
             }
-            ui.add(egui::TextEdit::singleline(&mut self.discovery_ip).hint_text("192.168.1.100"));
+            ui.add(egui::TextEdit::singleline(&mut self.discovery_ip).hint_text("192.168.1.100, 10.2.0.0/24, 10.2.0.10-50, host"));
             if ui.button("add").on_hover_text("add a device").clicked() {
-                let sender = self.cts.clone().unwrap();
-                let ip = self.discovery_ip.clone();
-                let mut rt = tokio::runtime::Runtime::new().unwrap();
-                rt.block_on(async move {sender.send(ip).await.expect("send failed");});
-                debug!("abc");
+                if let Some(cts) = &self.cts {
+                    if let Err(e) = cts.try_send(Command::AddPeer(self.discovery_ip.clone())) {
+                        info!("add peer failed: {}", e);
+                        self.status = format!("add failed: {}", e);
+                    }
+                }
+            }
+            if ui.button("share").on_hover_text("show the qr code other devices can scan to add this one").clicked() {
+                self.show_share = !self.show_share;
+            }
+            let mut visibility = self.settings.visibility;
+            egui::ComboBox::from_label("visible to")
+                .selected_text(visibility.label())
+                .show_ui(ui, |ui| {
+                    for v in Visibility::ALL {
+                        ui.selectable_value(&mut visibility, v, v.label());
+                    }
+                });
+            if visibility != self.settings.visibility {
+                self.settings.visibility = visibility;
+                self.settings_changed();
+            }
+            self.rooms_ui(ui);
+            if !self.status.is_empty() {
+                ui.label(&self.status);
             }
+            self.offers_ui(ui);
 
+            self.share_window(ctx);
+
+            let rooms = self.settings.rooms.clone();
             ui.vertical(|ui|{
                 let devices = self.devices.lock().unwrap();
                 if devices.is_empty() {
                     return;
                 }
-                let len = devices.len();
-                let row = (len-1) / 2 + 1;
-                
-                for i in 0..row {
-                    ui.with_layout(egui::Layout::left_to_right(egui::Align::Min),|ui|{
-                        for j in 0..2 {
-                            if let Some(dev) = devices.get(i*2+j) {
-                                device::Device{
-                                    name: dev.device.name.clone(),
-                                    ip: dev.addr.ip().to_string(),
-                                    r#type: dev.device.r#type.clone(),
-                                    id: dev.device.id.clone(),
-                                }.ui(ui);
-                            } else {
-                                break;
-                            }
-                        }
-                    });
+                if rooms.is_empty() {
+                    device_grid(ui, &devices.iter().collect::<Vec<_>>());
+                    return;
+                }
+                // a device in several joined rooms shows up in each of them
+                for room in &rooms {
+                    let members: Vec<_> = devices.iter().filter(|d| d.rooms.contains(room)).collect();
+                    if !members.is_empty() {
+                        ui.heading(room);
+                        device_grid(ui, &members);
+                    }
+                }
+                // outside the rooms only pinned devices stay visible
+                let pinned: Vec<_> = devices.iter().filter(|d| d.pinned && !d.rooms.iter().any(|r| rooms.contains(r))).collect();
+                if !pinned.is_empty() {
+                    ui.heading("pinned");
+                    device_grid(ui, &pinned);
                 }
             });
         });
     }
+
+    /// let the controller say goodbye and finish running transfers, but don't hang the exit
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        if let Some(cts) = self.cts.take() {
+            // the controller also stops once the channel is closed, after the queued commands
+            if let Err(e) = cts.try_send(Command::Shutdown) {
+                info!("can't queue the shutdown ({}), close the channel instead", e);
+            }
+            drop(cts);
+        }
+        let backend = match self.backend.take() {
+            Some(backend) => backend,
+            None => return,
+        };
+        let deadline = std::time::Instant::now() + BACKEND_STOP_TIMEOUT;
+        while !backend.is_finished() && std::time::Instant::now() < deadline {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+        if backend.is_finished() {
+            let _ = backend.join();
+        } else {
+            info!("backend didn't stop in {:?}", BACKEND_STOP_TIMEOUT);
+        }
+    }
 }
 
-pub fn start() -> std::io::Result<()> {
+pub fn start(config: Config, uris: Vec<String>) -> std::io::Result<()> {
     let options = eframe::NativeOptions {
         initial_window_size: Some(egui::vec2(500.0, 480.0)),
         resizable: false,
         ..Default::default()
     };
-    let app = MyApp::default();
+    let app = MyApp::new(config, uris);
 
     if let Err(e) = eframe::run_native(
         "RSDrop",
@@ -90,14 +326,52 @@ pub fn start() -> std::io::Result<()> {
     Ok(())
 }
 
-fn start_backend(ctx: egui::Context,devices: Arc<Mutex<Vec<RemoteTcpDevice>>>) -> std::io::Result<(tokio::sync::mpsc::Sender<String>)> {
-    let mut controller = controller::Controller::new(ctx);
+/// redraws the window when the controller changed something
+struct Repaint(egui::Context);
+
+impl Subscriber for Repaint {
+    fn changed(&self) {
+        self.0.request_repaint();
+    }
+}
+
+fn start_backend(ctx: egui::Context,config: Config,devices: Arc<Mutex<Vec<RemoteTcpDevice>>>,share: Arc<Mutex<String>>) -> std::io::Result<(tokio::sync::mpsc::Sender<Command>,tokio::sync::broadcast::Receiver<Event>,std::thread::JoinHandle<bool>)> {
+    let mut controller = controller::Controller::new(config);
+    controller.subscribe(Arc::new(Repaint(ctx.clone())));
     controller.set_device_container(devices);
-    let (ctx,crx) = controller.gen_ctx();
-    std::thread::spawn(move ||{
-        let mut rt = tokio::runtime::Runtime::new().unwrap();
-        rt.block_on(async move {controller.start_loop().await.expect("abc");});
-    });
-    
-    Ok(ctx)
+    controller.set_share_container(share);
+    let (cts,crx) = controller.gen_ctx();
+    let rt = tokio::runtime::Runtime::new()?;
+    let mut signals = {
+        let _rt = rt.enter();
+        Signals::new()?
+    };
+    // weak, closing the window still closes the channel
+    let signal_cts = cts.downgrade();
+    let backend = std::thread::Builder::new().name("backend".to_string()).spawn(move ||{
+        rt.block_on(async move {
+            let run = controller.start_loop();
+            tokio::pin!(run);
+            tokio::select! {
+                result = &mut run => {
+                    if let Err(e) = result {
+                        info!("controller stopped: {}", e);
+                    }
+                    return false;
+                },
+                signal = signals.recv() => info!("{}, shutting down", signal),
+            }
+            // the same shutdown as closing the window, then the window follows
+            if let Some(cts) = signal_cts.upgrade() {
+                let _ = cts.send(Command::Shutdown).await;
+            }
+            if let Err(e) = run.await {
+                info!("controller stopped: {}", e);
+            }
+            ctx.request_repaint();
+            true
+        })
+    })?;
+
+    Ok((cts,crx,backend))
 }
\ No newline at end of file
@@ -7,6 +7,9 @@ pub struct Device {
     pub ip: String,
     pub r#type: String,
     pub id: String,
+    pub pinned: bool,
+    pub reachable: bool,
+    pub compatible: bool,
 }
 
 impl Default for Device {
@@ -16,6 +19,9 @@ impl Default for Device {
             ip: "".to_string(),
             r#type: "".to_string(),
             id: "".to_string(),
+            pinned: false,
+            reachable: true,
+            compatible: true,
         }
     }
 }
@@ -27,8 +33,16 @@ impl Default for Device {
 /// |---------| |-----| |-----| |-----|
 impl Device {
     pub fn ui(&mut self, ui: &mut egui::Ui) {
+        // devices that didn't answer yet are dimmed, incompatible ones red
+        let fill = if !self.compatible {
+            egui::Color32::from_rgb(0x8b,0x2e,0x2e)
+        } else if self.reachable {
+            egui::Color32::from_rgb(0x3e,0x48,0x47)
+        } else {
+            egui::Color32::from_rgb(0x80,0x80,0x80)
+        };
         egui::Frame::none()
-            .fill(egui::Color32::from_rgb(0x3e,0x48,0x47))
+            .fill(fill)
             .rounding(egui::Rounding::same(5.0))
             .inner_margin(egui::Vec2::splat(2.0))
             .show(ui, |ui| {
@@ -46,6 +60,14 @@ impl Device {
                         ui.add(FusionLabel::new(type_text.color(egui::Color32::BLACK)).fill(bg)).on_hover_text("profile");
                         let ip_text: egui::WidgetText = format!("{}",self.ip).into();
                         ui.add(FusionLabel::new(ip_text.color(egui::Color32::BLACK)).fill(bg)).on_hover_text("profile");
+                        if !self.compatible {
+                            let text: egui::WidgetText = "incompatible".into();
+                            ui.add(FusionLabel::new(text.color(egui::Color32::BLACK)).fill(bg)).on_hover_text("protocol version can't talk to this one, sending is refused");
+                        }
+                        if self.pinned {
+                            let pinned_text: egui::WidgetText = "pinned".into();
+                            ui.add(FusionLabel::new(pinned_text.color(egui::Color32::BLACK)).fill(bg)).on_hover_text("added by drop:// uri");
+                        }
                     });
                 });
             });
@@ -1,9 +1,158 @@
+/// `USER-HOST` as the default device name
 pub fn hostname() -> String {
-    // Linux
-    let data = std::fs::read_to_string("/etc/hostname").unwrap();
-    let hostname = data.trim_end().to_string();
+    // Linux, other systems set the variable
+    let hostname = std::fs::read_to_string("/etc/hostname").ok()
+        .map(|data| data.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .or_else(|| std::env::var("HOSTNAME").ok().filter(|name| !name.is_empty()))
+        .or_else(|| std::env::var("COMPUTERNAME").ok().filter(|name| !name.is_empty()))
+        .unwrap_or_else(|| "rsdrop".to_string());
     if let Ok(user) = std::env::var("USER") {
         return format!("{}-{}",user.to_ascii_uppercase(),hostname.to_ascii_uppercase());
     }
     hostname
-}
\ No newline at end of file
+}
+
+/// match `text` against a shell style pattern, `*` matches any run of characters and `?` one character
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // position of the last `*` and the text position it was tried at
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((sp, st)) = star {
+            // let the `*` swallow one more character
+            p = sp + 1;
+            t = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// `$XDG_DATA_HOME/rsdrop`, or `~/.local/share/rsdrop`
+pub fn data_dir() -> Option<std::path::PathBuf> {
+    let base = match std::env::var_os("XDG_DATA_HOME") {
+        Some(dir) if !dir.is_empty() => std::path::PathBuf::from(dir),
+        _ => std::path::PathBuf::from(std::env::var_os("HOME")?).join(".local").join("share"),
+    };
+    Some(base.join("rsdrop"))
+}
+
+/// write a file only the current user can read, creating the parent directory
+pub fn write_private(path: &std::path::Path, data: &[u8]) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    std::io::Write::write_all(&mut file, data)
+}
+
+/// percent encode everything but unreserved uri characters
+pub fn percent_encode(text: &str) -> String {
+    let mut out = String::new();
+    for b in text.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+/// undo `percent_encode`, `None` for a broken escape or a result that isn't utf-8
+pub fn percent_decode(text: &str) -> Option<String> {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            // from_str_radix would take a sign, e.g. "%+1"
+            let hex = text.get(i + 1..i + 3).filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_match_literal() {
+        assert!(glob_match("eth0", "eth0"));
+        assert!(!glob_match("eth0", "eth1"));
+        assert!(!glob_match("eth0", "eth00"));
+        assert!(glob_match("", ""));
+        assert!(!glob_match("", "a"));
+    }
+
+    #[test]
+    fn glob_match_wildcards() {
+        assert!(glob_match("veth*", "veth"));
+        assert!(glob_match("veth*", "veth1a2b"));
+        assert!(!glob_match("veth*", "eth0"));
+        assert!(glob_match("*docker*", "docker0"));
+        assert!(glob_match("*docker*", "br-docker"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("eth?", "eth1"));
+        assert!(!glob_match("eth?", "eth"));
+        assert!(!glob_match("eth?", "eth10"));
+        // the `*` has to backtrack past the first `b`
+        assert!(glob_match("a*b?d", "abxbcd"));
+        assert!(glob_match("**a", "a"));
+        assert!(glob_match("w?*", "wlp3s0"));
+        // characters, not bytes
+        assert!(glob_match("例?", "例え"));
+    }
+
+    #[test]
+    fn percent_decode_escapes() {
+        assert_eq!(percent_decode("a%20b").as_deref(), Some("a b"));
+        assert_eq!(percent_decode("%E4%BE%8B").as_deref(), Some("例"));
+        assert_eq!(percent_decode("%e4%be%8b").as_deref(), Some("例"));
+        assert_eq!(percent_decode("plain").as_deref(), Some("plain"));
+        assert_eq!(percent_decode("").as_deref(), Some(""));
+    }
+
+    #[test]
+    fn percent_decode_invalid() {
+        assert_eq!(percent_decode("%"), None);
+        assert_eq!(percent_decode("%2"), None);
+        assert_eq!(percent_decode("%zz"), None);
+        assert_eq!(percent_decode("%+1"), None);
+        // a multi-byte character right after the escape
+        assert_eq!(percent_decode("%例"), None);
+        // not utf-8
+        assert_eq!(percent_decode("%FF"), None);
+    }
+
+    #[test]
+    fn percent_round_trip() {
+        for text in ["a b/c?d#e&f=g%", "例えば", "-._~"] {
+            assert_eq!(percent_decode(&percent_encode(text)).as_deref(), Some(text));
+        }
+    }
+}